@@ -1,15 +1,8 @@
 use proc_macro::TokenStream;
 
 use quote::{__private::Span, quote};
-use serde::{Deserialize, Serialize};
 use syn::{parse_macro_input, Ident, ItemFn};
 
-#[derive(Serialize, Deserialize)]
-struct HandlerMetadata {
-    pub input: String,
-    pub output: String,
-}
-
 pub fn handler(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let input: ItemFn = parse_macro_input!(item);
     let name = input.sig.ident;
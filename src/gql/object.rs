@@ -1,16 +1,9 @@
 use proc_macro::TokenStream;
 
 use quote::{__private::Span, quote, ToTokens};
-use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use syn::{parse_macro_input, Ident, ItemStruct, PathArguments, TypePath};
 
-#[derive(Serialize, Deserialize)]
-struct HandlerMetadata {
-    pub input: String,
-    pub output: String,
-}
-
 fn parse_type(i: String, p: &TypePath) -> (Value, Vec<(String, impl ToTokens)>) {
     let mut ext = vec![];
     let last_p = p.path.segments.last().unwrap().ident.to_string();
@@ -27,10 +20,7 @@ fn parse_type(i: String, p: &TypePath) -> (Value, Vec<(String, impl ToTokens)>)
             };
 
             let a = match arg {
-                syn::GenericArgument::Type(t) => match t {
-                    syn::Type::Path(p) => p,
-                    _ => panic!(),
-                },
+                syn::GenericArgument::Type(syn::Type::Path(p)) => p,
                 _ => panic!(),
             };
 
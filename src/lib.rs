@@ -18,11 +18,26 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
     sql::table::table(attr, item)
 }
 
+#[proc_macro_attribute]
+pub fn embedded(attr: TokenStream, item: TokenStream) -> TokenStream {
+    sql::embedded::embedded(attr, item)
+}
+
 #[proc_macro_attribute]
 pub fn select_from(attr: TokenStream, item: TokenStream) -> TokenStream {
     sql::select_from::select_from(attr, item)
 }
 
+#[proc_macro_derive(SqlEnum, attributes(sql_enum))]
+pub fn sql_enum(item: TokenStream) -> TokenStream {
+    sql::sql_enum::sql_enum(item)
+}
+
+#[proc_macro_attribute]
+pub fn key_provider(attr: TokenStream, item: TokenStream) -> TokenStream {
+    sql::key_provider(attr, item)
+}
+
 #[proc_macro_attribute]
 pub fn db_init(attr: TokenStream, item: TokenStream) -> TokenStream {
     sql::db_init(attr, item)
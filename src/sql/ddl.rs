@@ -0,0 +1,84 @@
+use riwaq_types::sql::TableDDLOp;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Column migration op, a superset of `riwaq_types::sql::DDLOp`.
+#[derive(Serialize, Clone, Debug)]
+pub enum DDLOp {
+    Keep,
+    Rename(String),
+    Retype {
+        from: String,
+        using: Option<String>,
+        renamed_from: Option<String>,
+    },
+    Drop,
+    DropAll,
+}
+
+impl DDLOp {
+    pub fn is_drop(&self) -> bool {
+        matches!(self, DDLOp::Drop | DDLOp::DropAll)
+    }
+}
+
+/// Referential action applied when the referenced row is deleted or updated.
+#[derive(Serialize, Clone, Debug)]
+pub enum RefAction {
+    NoAction,
+    Restrict,
+    Cascade,
+    SetNull,
+    SetDefault,
+}
+
+/// Foreign key constraint from a column to a column of another table.
+#[derive(Serialize, Clone, Debug)]
+pub struct ForeignKeyDDL {
+    pub tbl: String,
+    pub col: String,
+    pub on_delete: RefAction,
+    pub on_update: RefAction,
+}
+
+/// Column definition, a superset of `riwaq_types::sql::FieldDDL`.
+#[derive(Serialize, Clone, Debug)]
+pub struct FieldDDL {
+    pub name: String,
+    pub opt: bool,
+    pub ty: String,
+    pub default: Option<Value>,
+    pub op: DDLOp,
+    pub auto_increment: bool,
+    pub references: Option<ForeignKeyDDL>,
+}
+
+/// Secondary index or unique constraint over one or more columns.
+#[derive(Serialize, Clone, Debug)]
+pub struct IndexDDL {
+    pub name: String,
+    pub cols: Vec<String>,
+    pub unique: bool,
+    pub op: DDLOp,
+}
+
+/// Named CHECK constraint holding a SQL boolean expression.
+#[derive(Serialize, Clone, Debug)]
+pub struct CheckDDL {
+    pub name: String,
+    pub expr: String,
+}
+
+/// Table definition exported to the host through `riwaq_table_ddl_*`.
+#[derive(Serialize, Debug)]
+pub struct TableDDL {
+    pub name: String,
+    pub schema: Option<String>,
+    /// Database holding the table, `None` for the default one.
+    pub db: Option<String>,
+    pub cols: Vec<FieldDDL>,
+    pub op: TableDDLOp,
+    pub pk: Vec<String>,
+    pub indexes: Vec<IndexDDL>,
+    pub checks: Vec<CheckDDL>,
+}
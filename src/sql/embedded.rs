@@ -0,0 +1,294 @@
+use convert_case::{Case, Casing};
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, DeriveInput};
+
+use super::{
+    ddl::FieldDDL,
+    schema_lock,
+    table::{
+        column_filters, field_to_ddl, has_flag, is_sql_enum, option_inner, rust_type, sum_type,
+    },
+};
+
+/// Name of the hidden module holding the columns of an `#[embedded]` struct.
+pub fn embedded_mod(name: &Ident) -> Ident {
+    Ident::new(
+        &format!("__riwaq_embedded_{}", name.to_string().to_case(Case::Snake)),
+        name.span(),
+    )
+}
+
+/// Schema lock key and DDL of an `#[embedded]` struct, its columns are locked once, unprefixed,
+/// instead of with each table flattening it.
+fn embedded_lock(name: &Ident, ddls: &[FieldDDL]) -> (String, String) {
+    (
+        format!("embedded:{}", name),
+        serde_json::json!({ "cols": ddls }).to_string(),
+    )
+}
+
+pub fn embedded(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(item as DeriveInput);
+    let name = input.ident.clone();
+    let vis = input.vis.clone();
+    let snake = name.to_string().to_case(Case::Snake);
+    let hidden = embedded_mod(&name);
+
+    let fields = match &input.data {
+        syn::Data::Struct(s) => s.fields.clone(),
+        _ => panic!("struct must have named fields"),
+    };
+    let ddls = fields
+        .iter()
+        .map(|f| {
+            if has_flag(
+                f,
+                &[
+                    "primary_key",
+                    "pk",
+                    "index",
+                    "unique",
+                    "references",
+                    "foreign_key",
+                    "auto_increment",
+                    "autoincrement",
+                    "identity",
+                    "flatten",
+                    "created_at",
+                    "updated_at",
+                    "version",
+                    "encrypted",
+                    "check",
+                    "range",
+                    "max_len",
+                    "min_len",
+                ],
+            ) {
+                panic!(
+                    "embedded column '{}' should not be part of a key, an index or a reference, nor have a check",
+                    f.ident.as_ref().unwrap()
+                );
+            }
+            let ddl = field_to_ddl(f);
+            if ddl.op.is_drop() {
+                panic!("embedded column '{}' should not be dropped", ddl.name);
+            }
+            ddl
+        })
+        .collect::<Vec<_>>();
+
+    let field_names = fields
+        .iter()
+        .map(|f| f.ident.as_ref().expect("field sould have a name"))
+        .collect::<Vec<_>>();
+    let f_names = field_names
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>();
+    let col_names = ddls.iter().map(|ddl| &ddl.name).collect::<Vec<_>>();
+    let cols = field_names
+        .iter()
+        .map(|f| Ident::new(&f.to_string().to_case(Case::Pascal), Span::call_site()))
+        .collect::<Vec<_>>();
+    let aliases = field_names
+        .iter()
+        .map(|f| format_ident!("__riwaq_embedded_{}_{}", snake, f))
+        .collect::<Vec<_>>();
+    let types = fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+    let filters = field_names
+        .iter()
+        .map(|f| column_filters(&quote!(super::__embedded::#f), None))
+        .collect::<Vec<_>>();
+
+    let enum_patches = fields
+        .iter()
+        .zip(aliases.iter())
+        .enumerate()
+        .filter(|(_, (f, _))| is_sql_enum(&rust_type(&f.ty).0))
+        .map(|(i, (f, alias))| {
+            let ty = match option_inner(&f.ty) {
+                Some(_) => format_ident!("{}_inner", alias),
+                None => alias.clone(),
+            };
+            quote! {
+                cols[#i]["ty"] = riwaq::serde_json::Value::from(super::#ty::SQL_TYPE);
+            }
+        })
+        .collect::<Vec<_>>();
+    let inner_aliases = fields
+        .iter()
+        .zip(aliases.iter())
+        .filter_map(|(f, alias)| {
+            let inner = option_inner(&f.ty)?;
+            let id = format_ident!("{}_inner", alias);
+            Some(quote!(type #id = #inner;))
+        })
+        .collect::<Vec<_>>();
+
+    let inner_types = fields
+        .iter()
+        .zip(aliases.iter())
+        .map(|(f, alias)| match option_inner(&f.ty) {
+            Some(_) => format_ident!("{}_inner", alias),
+            None => alias.clone(),
+        })
+        .collect::<Vec<_>>();
+    let sum_types = fields
+        .iter()
+        .zip(field_names.iter())
+        .map(|(f, name)| {
+            sum_type(&rust_type(&f.ty).0, quote!(__embedded::inner::#name))
+                .unwrap_or(quote!(super::NotNumeric))
+        })
+        .collect::<Vec<_>>();
+
+    let ddl_json = serde_json::to_string(&ddls).unwrap();
+    let (lock_key, lock_json) = embedded_lock(&name, &ddls);
+    let sql_enums = fields
+        .iter()
+        .zip(ddls.iter())
+        .filter(|(f, _)| is_sql_enum(&rust_type(&f.ty).0))
+        .map(|(_, ddl)| ddl.name.as_str())
+        .collect::<Vec<_>>();
+    let schema_lock = schema_lock::check(&[(&lock_key, &lock_json)], &sql_enums, name.span());
+
+    if let syn::Data::Struct(s) = &mut input.data {
+        for f in s.fields.iter_mut() {
+            f.attrs.retain(|a| a.path().is_ident("doc"));
+        }
+    }
+
+    TokenStream::from(quote! {
+        #[derive(riwaq::serde::Serialize, riwaq::serde::Deserialize)]
+        #input
+
+        #schema_lock
+
+        #(
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            type #aliases = #types;
+        )*
+        #(
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            #inner_aliases
+        )*
+
+        #[doc(hidden)]
+        #[allow(non_snake_case, non_camel_case_types)]
+        #vis mod #hidden {
+            #(pub type #field_names = super::#aliases;)*
+
+            pub mod inner {
+                #(pub type #field_names = super::super::#inner_types;)*
+            }
+
+            const FIELDS: &[(&str, &str)] = &[#((#f_names, #col_names),)*];
+
+            pub fn columns(prefix: &str) -> Vec<riwaq::serde_json::Value> {
+                let mut cols = riwaq::serde_json::from_str::<Vec<riwaq::serde_json::Value>>(#ddl_json).unwrap();
+                #(#enum_patches)*
+                for col in cols.iter_mut() {
+                    col["name"] = riwaq::serde_json::Value::from(format!("{}{}", prefix, col["name"].as_str().unwrap()));
+                    if let Some(from) = col["op"]["Rename"].as_str().map(|from| format!("{}{}", prefix, from)) {
+                        col["op"]["Rename"] = riwaq::serde_json::Value::from(from);
+                    }
+                    if let Some(from) = col["op"]["Retype"]["renamed_from"].as_str().map(|from| format!("{}{}", prefix, from)) {
+                        col["op"]["Retype"]["renamed_from"] = riwaq::serde_json::Value::from(from);
+                    }
+                }
+                cols
+            }
+
+            pub fn read(
+                prefix: &str,
+                row: &mut riwaq::serde_json::Map<String, riwaq::serde_json::Value>,
+            ) -> Result<Option<riwaq::serde_json::Value>, String> {
+                Ok(Some(riwaq::serde_json::Value::Object(
+                    FIELDS
+                        .iter()
+                        .filter_map(|(field, col)| {
+                            row.remove(&format!("{}{}", prefix, col))
+                                .map(|v| (field.to_string(), v))
+                        })
+                        .collect(),
+                )))
+            }
+
+            pub fn write(prefix: &str, value: riwaq::serde_json::Value) -> Vec<(String, riwaq::serde_json::Value)> {
+                match value {
+                    riwaq::serde_json::Value::Object(mut value) => FIELDS
+                        .iter()
+                        .filter_map(|(field, col)| {
+                            value.remove(*field).map(|v| (format!("{}{}", prefix, col), v))
+                        })
+                        .collect(),
+                    _ => vec![],
+                }
+            }
+
+            #[allow(unused_macros)]
+            macro_rules! column_mods {
+                ($prefix:literal) => {
+                    pub const PREFIX: &str = $prefix;
+                    #[doc(hidden)]
+                    pub const COLS: &[&str] = &[#(concat!($prefix, #col_names),)*];
+
+                    #[doc(hidden)]
+                    pub fn read(
+                        row: &mut riwaq::serde_json::Map<String, riwaq::serde_json::Value>,
+                    ) -> Result<Option<riwaq::serde_json::Value>, String> {
+                        __embedded::read(PREFIX, row)
+                    }
+
+                    #[doc(hidden)]
+                    pub fn write(value: riwaq::serde_json::Value) -> Vec<(String, riwaq::serde_json::Value)> {
+                        __embedded::write(PREFIX, value)
+                    }
+
+                    #(
+                        #[allow(non_upper_case_globals)]
+                        pub const #cols: super::Column<__embedded::inner::#field_names, #sum_types> =
+                            super::Column::new(#cols::COL);
+                    )*
+
+                    #(
+                        pub mod #cols {
+                            pub const COL: &str = concat!($prefix, #col_names);
+
+                            #filters
+                        }
+                    )*
+                };
+            }
+            #[allow(unused_imports)]
+            pub(crate) use column_mods;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::{parse::Parser, parse_quote, Field};
+
+    use super::*;
+
+    #[test]
+    fn locked_columns() {
+        let ddls = [
+            quote!(city: String),
+            quote!(#[rename_from("zip")] postal_code: Option<i32>),
+        ]
+        .map(|f| field_to_ddl(&Field::parse_named.parse2(f).unwrap()));
+        let (key, json) = embedded_lock(&parse_quote!(Address), &ddls);
+        assert_eq!(key, "embedded:Address");
+        let ddl = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(ddl["cols"][0]["name"], "city");
+        assert_eq!(ddl["cols"][1]["name"], "postal_code");
+        assert_eq!(ddl["cols"][1]["ty"], "INT");
+        assert_eq!(ddl["cols"][1]["op"]["Rename"], "zip");
+    }
+}
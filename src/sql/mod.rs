@@ -1,9 +1,14 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, ItemFn};
+use syn::{parse_macro_input, ItemFn, ItemImpl};
 
+mod ddl;
+pub mod embedded;
+mod schema_lock;
 pub mod select_from;
+pub mod sql_enum;
 pub mod table;
+mod table_opts;
 
 pub fn db_init(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let input: ItemFn = parse_macro_input!(item);
@@ -25,3 +30,28 @@ pub fn db_init(_attr: TokenStream, item: TokenStream) -> TokenStream {
         }
     })
 }
+
+/// Registers the default key provider of the `#[encrypted]` columns. The impl has to sit at the
+/// crate root, where the columns name it, or else be given to them as
+/// `#[encrypted(provider = path::to::Provider)]`.
+pub fn key_provider(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input: ItemImpl = parse_macro_input!(item);
+
+    let is_key_provider = input
+        .trait_
+        .as_ref()
+        .and_then(|(_, path, _)| path.segments.last())
+        .is_some_and(|s| s.ident == "KeyProvider");
+    if !is_key_provider {
+        panic!("key_provider should be declared on an impl of riwaq::sql::KeyProvider");
+    }
+    let provider = &input.self_ty;
+
+    // named by the `#[encrypted]` columns without a provider as `crate::__RiwaqKeyProvider`
+    TokenStream::from(quote! {
+        #input
+
+        #[doc(hidden)]
+        pub(crate) type __RiwaqKeyProvider = #provider;
+    })
+}
@@ -0,0 +1,469 @@
+use std::{env, fs, path::PathBuf, sync::Mutex};
+
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, quote_spanned};
+use serde_json::{Map, Value};
+
+/// Schema snapshot checked in next to `Cargo.toml`.
+const LOCKFILE: &str = "riwaq.schema.json";
+
+/// Set to regenerate the snapshot instead of checking against it.
+const UPDATE_VAR: &str = "RIWAQ_SCHEMA_UPDATE";
+
+const INT_TYPES: [&str; 4] = ["TINYINT", "SMALLINT", "INT", "BIGINT"];
+
+/// Tables of each crate seen by this compilation in update mode, see `check`.
+static UPDATED: Mutex<Vec<(String, Map<String, Value>)>> = Mutex::new(vec![]);
+
+fn lockfile() -> Option<PathBuf> {
+    env::var_os("CARGO_MANIFEST_DIR").map(|dir| PathBuf::from(dir).join(LOCKFILE))
+}
+
+/// The snapshot is grouped by crate, so the tables of tests and examples do not collide.
+fn crate_name() -> String {
+    env::var("CARGO_CRATE_NAME").unwrap_or_default()
+}
+
+fn read(path: &PathBuf) -> Option<Map<String, Value>> {
+    let content = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&content) {
+        Ok(Value::Object(tables)) => Some(tables),
+        _ => panic!("invalid schema lockfile '{}'", path.display()),
+    }
+}
+
+fn is_drop(op: &Value) -> bool {
+    matches!(op.as_str(), Some("Drop") | Some("DropAll"))
+}
+
+/// `(base, size, scale)` of a column type, e.g. `("DECIMAL", Some(10), Some(2))`.
+fn split_type(ty: &str) -> (String, Option<u64>, Option<u64>) {
+    let ty = ty.trim().to_uppercase();
+    match ty.split_once('(') {
+        Some((base, args)) => {
+            let mut args = args
+                .trim_end_matches(')')
+                .split(',')
+                .map(|a| a.trim().parse().ok());
+            (
+                base.trim().to_string(),
+                args.next().flatten(),
+                args.next().flatten(),
+            )
+        }
+        None => (ty, None, None),
+    }
+}
+
+/// Whether values of type `from` always fit in type `to`.
+fn is_widening(from: &str, to: &str) -> bool {
+    let ((from, from_size, from_scale), (to, to_size, to_scale)) =
+        (split_type(from), split_type(to));
+    let (from_unsigned, to_unsigned) = (from.ends_with(" UNSIGNED"), to.ends_with(" UNSIGNED"));
+    let int_rank = |ty: &str| {
+        INT_TYPES
+            .iter()
+            .position(|i| *i == ty.trim_end_matches(" UNSIGNED"))
+    };
+    match (int_rank(&from), int_rank(&to)) {
+        (Some(f), Some(t)) => {
+            return (from_unsigned == to_unsigned && f <= t)
+                || (from_unsigned && !to_unsigned && f < t)
+        }
+        (Some(_), None) | (None, Some(_)) => return false,
+        _ => {}
+    }
+    match (from.as_str(), to.as_str()) {
+        ("FLOAT", "DOUBLE") => true,
+        ("CHAR" | "VARCHAR", "TEXT") => true,
+        // neither the integer digits nor the fractional ones may shrink
+        ("DECIMAL" | "NUMERIC", "DECIMAL" | "NUMERIC") => match (from_size, to_size) {
+            (Some(f), Some(t)) => {
+                let (f_scale, t_scale) = (from_scale.unwrap_or(0), to_scale.unwrap_or(0));
+                f_scale <= t_scale && f.saturating_sub(f_scale) <= t.saturating_sub(t_scale)
+            }
+            (_, None) => true,
+            (None, Some(_)) => false,
+        },
+        (f, t) if f == t || (f == "CHAR" && t == "VARCHAR") => match (from_size, to_size) {
+            (Some(f), Some(t)) => f <= t,
+            (_, None) => true,
+            (None, Some(_)) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Destructive changes from the `locked` table to the new `ddl`, not acknowledged by a marker.
+fn destructive_changes(table: &str, locked: &Value, ddl: &Value) -> Vec<String> {
+    if is_drop(&locked["op"]) {
+        return vec![];
+    }
+    let cols = ddl["cols"].as_array().unwrap();
+    locked["cols"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|old| !is_drop(&old["op"]))
+        .filter_map(|old| {
+            let name = old["name"].as_str()?;
+            let col = cols.iter().find(|c| {
+                c["op"]["Rename"].as_str() == Some(name)
+                    || c["op"]["Retype"]["renamed_from"].as_str() == Some(name)
+            });
+            let col = col.or_else(|| {
+                cols.iter()
+                    .find(|c| c["name"].as_str() == Some(name) && c["op"]["Rename"].is_null())
+            });
+            let Some(col) = col else {
+                return Some(format!(
+                    "column '{}' of table '{}' was removed, keep the field marked #[dropped] to drop it or #[rename_from(\"{}\")] the field replacing it",
+                    name, table, name
+                ));
+            };
+            if is_drop(&col["op"]) || col["op"].get("Retype").is_some() {
+                return None;
+            }
+            let (from, to) = (old["ty"].as_str()?, col["ty"].as_str()?);
+            // the SQL type of a SqlEnum is only known at runtime, only the enum itself is locked
+            if (old["sql_enum"] == true || col["sql_enum"] == true)
+                && (old["sql_enum"] != col["sql_enum"] || from != to)
+            {
+                return Some(format!(
+                    "column '{}' of table '{}' changed type from {} to {}, which cannot be verified for a SqlEnum, mark the field #[retype_from(..)] with its previous type to convert it",
+                    name, table, from, to
+                ));
+            }
+            if !is_widening(from, to) {
+                return Some(format!(
+                    "column '{}' of table '{}' changed type from {} to {}, mark the field #[retype_from(..)] with its previous type to convert it",
+                    name, table, from, to
+                ));
+            }
+            if old["opt"] == true && col["opt"] == false && col["default"].is_null() {
+                return Some(format!(
+                    "column '{}' of table '{}' is no longer optional, give the field a #[default(..)] for its NULL values",
+                    name, table
+                ));
+            }
+            None
+        })
+        .collect()
+}
+
+/// Key of the table renamed `from`, in the same database and schema as `key`.
+fn renamed_key(key: &str, from: &str) -> String {
+    match key.rfind(['.', ':']) {
+        Some(i) => format!("{}{}", &key[..=i], from),
+        None => from.to_string(),
+    }
+}
+
+/// Checks the table DDLs, keyed by table path prefixed with `<db>:` outside the default
+/// database, against the schema lockfile.
+/// `sql_enums` are the columns typed with a SqlEnum.
+/// With `RIWAQ_SCHEMA_UPDATE` set the crate snapshot is rewritten with the tables seen by this
+/// compilation instead. It is only written when one of them changed, so an unchanged schema does
+/// not touch the lockfile nor trigger a rebuild, and removed tables are pruned by the next change.
+pub fn check(tables: &[(&str, &str)], sql_enums: &[&str], span: Span) -> TokenStream {
+    let Some(path) = lockfile() else {
+        return quote!();
+    };
+    let crate_name = crate_name();
+    let tables = tables
+        .iter()
+        .map(|(key, ddl)| {
+            let mut ddl = serde_json::from_str::<Value>(ddl).unwrap();
+            for col in ddl["cols"].as_array_mut().into_iter().flatten() {
+                if col["name"]
+                    .as_str()
+                    .is_some_and(|name| sql_enums.contains(&name))
+                {
+                    col["sql_enum"] = Value::Bool(true);
+                }
+            }
+            (*key, ddl)
+        })
+        .collect::<Vec<_>>();
+
+    let mut updated = UPDATED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut locked = read(&path);
+
+    let errors = if env::var_os(UPDATE_VAR).is_some_and(|v| !v.is_empty() && v != "0") {
+        let seen = match updated.iter().position(|(name, _)| *name == crate_name) {
+            Some(i) => &mut updated[i].1,
+            None => {
+                updated.push((crate_name.to_owned(), Map::new()));
+                &mut updated.last_mut().unwrap().1
+            }
+        };
+        for (key, ddl) in tables {
+            seen.insert(key.to_string(), ddl);
+        }
+        let snapshot = locked.get_or_insert_with(Map::new);
+        let changed = !snapshot
+            .get(&crate_name)
+            .and_then(Value::as_object)
+            .is_some_and(|locked| seen.iter().all(|(key, ddl)| locked.get(key) == Some(ddl)));
+        if changed {
+            snapshot.insert(crate_name, Value::Object(seen.clone()));
+            let content = serde_json::to_string_pretty(snapshot).unwrap() + "\n";
+            fs::write(&path, content)
+                .unwrap_or_else(|e| panic!("cannot write '{}': {}", path.display(), e));
+        }
+        vec![]
+    } else {
+        let crate_tables = locked.as_ref().and_then(|locked| locked.get(&crate_name));
+        tables
+            .iter()
+            .flat_map(|(key, ddl)| {
+                if is_drop(&ddl["op"]) {
+                    return vec![];
+                }
+                let renamed = ddl["op"]["Rename"]
+                    .as_str()
+                    .map(|from| renamed_key(key, from));
+                let old = crate_tables.and_then(|locked| {
+                    locked
+                        .get(*key)
+                        .or_else(|| renamed.and_then(|from| locked.get(&from)))
+                });
+                match old {
+                    Some(old) => destructive_changes(key, old, ddl),
+                    None => vec![],
+                }
+            })
+            .collect()
+    };
+    drop(updated);
+
+    // makes cargo rebuild the tables when the lockfile or the update mode changes
+    let path = path.display().to_string();
+    let tracked = match locked.is_some() {
+        true => quote!(
+            const _: &[u8] = include_bytes!(#path);
+        ),
+        false => quote!(),
+    };
+    quote_spanned! {span=>
+        #tracked
+        const _: Option<&str> = option_env!(#UPDATE_VAR);
+        #(compile_error!(#errors);)*
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn col(name: &str, ty: &str, opt: bool, op: Value) -> Value {
+        json!({ "name": name, "opt": opt, "ty": ty, "default": null, "op": op })
+    }
+
+    fn table(cols: Vec<Value>) -> Value {
+        json!({ "name": "user", "op": "Keep", "cols": cols })
+    }
+
+    fn changes(old: Vec<Value>, new: Vec<Value>) -> Vec<String> {
+        destructive_changes("user", &table(old), &table(new))
+    }
+
+    #[test]
+    fn unchanged_table() {
+        let cols = vec![col("id", "BIGINT", false, json!("Keep"))];
+        assert!(changes(cols.clone(), cols).is_empty());
+    }
+
+    #[test]
+    fn renamed_table_keys() {
+        assert_eq!(renamed_key("users", "user"), "user");
+        assert_eq!(renamed_key("app.users", "user"), "app.user");
+        assert_eq!(renamed_key("main:users", "user"), "main:user");
+        assert_eq!(renamed_key("main:app.users", "user"), "main:app.user");
+    }
+
+    #[test]
+    fn renamed_column() {
+        let old = vec![col("name", "VARCHAR(64)", false, json!("Keep"))];
+        let marked = vec![col(
+            "login",
+            "VARCHAR(64)",
+            false,
+            json!({ "Rename": "name" }),
+        )];
+        assert!(changes(old.clone(), marked).is_empty());
+
+        let unmarked = vec![col("login", "VARCHAR(64)", false, json!("Keep"))];
+        let errors = changes(old, unmarked);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("column 'name' of table 'user' was removed"));
+    }
+
+    #[test]
+    fn retyped_column() {
+        let old = vec![col("age", "VARCHAR(8)", false, json!("Keep"))];
+        let retype =
+            json!({ "Retype": { "from": "VARCHAR(8)", "using": null, "renamed_from": null } });
+        let marked = vec![col("age", "INT", false, retype)];
+        assert!(changes(old.clone(), marked).is_empty());
+
+        let unmarked = vec![col("age", "INT", false, json!("Keep"))];
+        let errors = changes(old, unmarked);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("changed type from VARCHAR(8) to INT"));
+    }
+
+    #[test]
+    fn retyped_and_renamed_column() {
+        let old = vec![col("age", "VARCHAR(8)", false, json!("Keep"))];
+        let retype =
+            json!({ "Retype": { "from": "VARCHAR(8)", "using": null, "renamed_from": "age" } });
+        let new = vec![col("years", "INT", false, retype)];
+        assert!(changes(old, new).is_empty());
+    }
+
+    #[test]
+    fn dropped_column() {
+        let old = vec![
+            col("id", "BIGINT", false, json!("Keep")),
+            col("bio", "TEXT", true, json!("Keep")),
+        ];
+        let marked = vec![
+            col("id", "BIGINT", false, json!("Keep")),
+            col("bio", "TEXT", true, json!("Drop")),
+        ];
+        assert!(changes(old.clone(), marked.clone()).is_empty());
+        // once dropped, the field can be removed
+        assert!(changes(marked, vec![col("id", "BIGINT", false, json!("Keep"))]).is_empty());
+
+        let errors = changes(old, vec![col("id", "BIGINT", false, json!("Keep"))]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("column 'bio' of table 'user' was removed"));
+    }
+
+    #[test]
+    fn dropped_table() {
+        let old = json!({ "name": "user", "op": "Drop", "cols": [] });
+        let new = table(vec![col("id", "BIGINT", false, json!("Keep"))]);
+        assert!(destructive_changes("user", &old, &new).is_empty());
+    }
+
+    #[test]
+    fn narrowed_int() {
+        let old = vec![col("n", "INT", false, json!("Keep"))];
+        let errors = changes(
+            old.clone(),
+            vec![col("n", "SMALLINT", false, json!("Keep"))],
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("changed type from INT to SMALLINT"));
+
+        assert!(changes(old, vec![col("n", "BIGINT", false, json!("Keep"))]).is_empty());
+    }
+
+    #[test]
+    fn narrowed_varchar() {
+        let old = vec![col("s", "VARCHAR(255)", false, json!("Keep"))];
+        let errors = changes(
+            old.clone(),
+            vec![col("s", "VARCHAR(64)", false, json!("Keep"))],
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("changed type from VARCHAR(255) to VARCHAR(64)"));
+
+        assert!(changes(
+            old.clone(),
+            vec![col("s", "VARCHAR(1024)", false, json!("Keep"))]
+        )
+        .is_empty());
+        assert!(changes(old, vec![col("s", "TEXT", false, json!("Keep"))]).is_empty());
+    }
+
+    #[test]
+    fn nullable_to_not_null() {
+        let old = vec![col("s", "TEXT", true, json!("Keep"))];
+        let errors = changes(old.clone(), vec![col("s", "TEXT", false, json!("Keep"))]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("column 's' of table 'user' is no longer optional"));
+
+        let mut defaulted = col("s", "TEXT", false, json!("Keep"));
+        defaulted["default"] = json!("");
+        assert!(changes(old, vec![defaulted]).is_empty());
+        // relaxing NOT NULL is always safe
+        assert!(changes(
+            vec![col("s", "TEXT", false, json!("Keep"))],
+            vec![col("s", "TEXT", true, json!("Keep"))]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn narrowed_decimal() {
+        let old = vec![col("price", "DECIMAL(10, 2)", false, json!("Keep"))];
+        let errors = changes(
+            old.clone(),
+            vec![col("price", "DECIMAL(10, 0)", false, json!("Keep"))],
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("changed type from DECIMAL(10, 2) to DECIMAL(10, 0)"));
+        // more fractional digits at the expense of the integer ones
+        assert_eq!(
+            changes(
+                old.clone(),
+                vec![col("price", "DECIMAL(10, 4)", false, json!("Keep"))]
+            )
+            .len(),
+            1
+        );
+        assert!(changes(
+            old,
+            vec![col("price", "NUMERIC(12, 4)", false, json!("Keep"))]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn sql_enum_column() {
+        let mut status = col("status", "Status", false, json!("Keep"));
+        status["sql_enum"] = json!(true);
+        assert!(changes(vec![status.clone()], vec![status.clone()]).is_empty());
+
+        let mut state = col("status", "State", false, json!("Keep"));
+        state["sql_enum"] = json!(true);
+        let errors = changes(vec![status.clone()], vec![state]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("cannot be verified for a SqlEnum"));
+
+        let text = col("status", "VARCHAR(16)", false, json!("Keep"));
+        assert_eq!(changes(vec![text.clone()], vec![status.clone()]).len(), 1);
+        assert_eq!(changes(vec![status.clone()], vec![text]).len(), 1);
+
+        let retype =
+            json!({ "Retype": { "from": "VARCHAR(16)", "using": null, "renamed_from": null } });
+        status["op"] = retype;
+        assert!(changes(
+            vec![col("status", "VARCHAR(16)", false, json!("Keep"))],
+            vec![status]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn widening() {
+        assert!(is_widening("TINYINT", "INT"));
+        assert!(is_widening("INT UNSIGNED", "BIGINT"));
+        assert!(is_widening("FLOAT", "DOUBLE"));
+        assert!(is_widening("CHAR(4)", "VARCHAR(4)"));
+        assert!(is_widening("varchar(8)", "VARCHAR(16)"));
+        assert!(is_widening("DECIMAL(10, 2)", "DECIMAL(11, 3)"));
+        assert!(is_widening("DECIMAL(10)", "DECIMAL(10, 0)"));
+        assert!(!is_widening("DECIMAL(10, 2)", "DECIMAL(12, 1)"));
+        assert!(!is_widening("INT UNSIGNED", "INT"));
+        assert!(!is_widening("INT", "INT UNSIGNED"));
+        assert!(!is_widening("BIGINT", "DOUBLE"));
+        assert!(!is_widening("TEXT", "VARCHAR(65535)"));
+        assert!(!is_widening("VARCHAR", "VARCHAR(10)"));
+    }
+}
@@ -1,110 +1,774 @@
+use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use proc_macro2::Span;
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput, Ident, Path, Type};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    DeriveInput, Ident, Meta, Path, Token, Type,
+};
+
+/// `[left] join Table on Table::Col == Other::Col && ...`
+struct Join {
+    left: bool,
+    table: Path,
+    on: Vec<(Path, Path)>,
+}
+
+/// `#[select_from(Table [join ...]*, group_by(field, ...))]`
+struct SelectFromOpts {
+    table: Path,
+    joins: Vec<Join>,
+    group_by: Option<Vec<Ident>>,
+}
+
+impl Parse for Join {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let kw = input.parse::<Ident>()?;
+        let left = kw == "left";
+        let kw = match left {
+            true => input.parse::<Ident>()?,
+            false => kw,
+        };
+        if kw != "join" {
+            return Err(syn::Error::new(kw.span(), "expected 'join' or 'left join'"));
+        }
+        let table = input.parse::<Path>()?;
+        let kw = input.parse::<Ident>()?;
+        if kw != "on" {
+            return Err(syn::Error::new(kw.span(), "expected 'on'"));
+        }
+        let mut on = vec![];
+        loop {
+            let l = input.parse::<Path>()?;
+            input.parse::<Token![==]>()?;
+            on.push((l, input.parse::<Path>()?));
+            if input.parse::<Option<Token![&&]>>()?.is_none() {
+                break;
+            }
+        }
+        Ok(Join { left, table, on })
+    }
+}
+
+impl Parse for SelectFromOpts {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let table = input.parse::<Path>()?;
+        let mut joins = vec![];
+        while input.peek(Ident) {
+            joins.push(input.parse::<Join>()?);
+        }
+        let mut group_by = None;
+        if input.is_empty() {
+            return Ok(SelectFromOpts {
+                table,
+                joins,
+                group_by,
+            });
+        }
+        input.parse::<Token![,]>()?;
+        for meta in Punctuated::<Meta, Token![,]>::parse_terminated(input)? {
+            if !meta.path().is_ident("group_by") || group_by.is_some() {
+                return Err(syn::Error::new_spanned(
+                    meta,
+                    "expected a single option: group_by(field, ...)",
+                ));
+            }
+            group_by = Some(
+                meta.require_list()?
+                    .parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?
+                    .into_iter()
+                    .collect::<Vec<_>>(),
+            );
+        }
+        Ok(SelectFromOpts {
+            table,
+            joins,
+            group_by,
+        })
+    }
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    a.to_token_stream().to_string() == b.to_token_stream().to_string()
+}
+
+/// Table of a `Table::Col` path.
+fn table_of(col: &Path) -> Path {
+    let mut table = col.clone();
+    table.segments.pop();
+    table.segments.pop_punct();
+    table
+}
+
+fn col_mod(f: &Ident) -> Ident {
+    Ident::new(&f.to_string().to_case(Case::Pascal), Span::call_site())
+}
+
+/// Aggregate of an `#[agg(...)]` field: `count`, `count(col)`, `sum(col)`, `avg(col)`, `min(col)` or `max(col)`,
+/// and whether it is a count, which is never null.
+fn aggregate(table_p: &Path, meta: &Meta) -> (proc_macro2::TokenStream, bool) {
+    let func = meta
+        .path()
+        .get_ident()
+        .map(|id| id.to_string())
+        .filter(|func| ["count", "sum", "avg", "min", "max"].contains(&func.as_str()))
+        .unwrap_or_else(|| panic!("agg should be one of: 'count', 'sum', 'avg', 'min', 'max'"));
+    let func = Ident::new(&func, meta.path().get_ident().unwrap().span());
+    let count = func == "count";
+    let agg = match meta {
+        Meta::Path(_) if count => quote!(#table_p::count()),
+        Meta::List(list) => {
+            let col = col_mod(
+                &list
+                    .parse_args::<Ident>()
+                    .unwrap_or_else(|_| panic!("agg {} should take a single column", func)),
+            );
+            quote!(#table_p::#col.#func())
+        }
+        _ => panic!("agg {} should be given a column: {}(field)", func, func),
+    };
+    (agg, count)
+}
 
 pub fn select_from(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let table_p = parse_macro_input!(attr as Path);
+    let opts = parse_macro_input!(attr as SelectFromOpts);
     let input = parse_macro_input!(item as DeriveInput);
+    TokenStream::from(expand(opts, input))
+}
+
+fn expand(opts: SelectFromOpts, mut input: DeriveInput) -> proc_macro2::TokenStream {
+    let table_p = opts.table;
+    let joined = !opts.joins.is_empty();
+    let tables = std::iter::once(table_p.clone())
+        .chain(opts.joins.iter().map(|j| j.table.clone()))
+        .collect::<Vec<_>>();
+    for (i, t) in tables.iter().enumerate() {
+        if tables[..i].iter().any(|p| same_path(p, t)) {
+            panic!("table '{}' should be joined only once", t.to_token_stream());
+        }
+    }
+    let table_index = |t: &Path| {
+        tables
+            .iter()
+            .position(|p| same_path(p, t))
+            .unwrap_or_else(|| {
+                panic!(
+                    "'{}' should be one of the selected tables",
+                    t.to_token_stream()
+                )
+            })
+    };
     let id = input.ident.clone();
     let impl_id = Ident::new(&format!("{}Query", &id), Span::call_site());
+    let page_id = Ident::new(&format!("{}Page", &id), Span::call_site());
+    let filter_id = Ident::new(&format!("{}Filter", &id), Span::call_site());
+    let order_id = Ident::new(&format!("{}Order", &id), Span::call_site());
 
-    let (idents, types): (Vec<Ident>, Vec<Type>) = match input.clone().data {
+    let mut fields = vec![];
+    let mut aggs = vec![];
+    match &mut input.data {
         syn::Data::Struct(s) => s,
         _ => panic!("struct must have named fields"),
     }
     .fields
-    .into_iter()
-    .filter_map(|field| field.ident.map(|f| (f, field.ty)))
-    .unzip();
+    .iter_mut()
+    .for_each(|field| {
+        let agg = field
+            .attrs
+            .iter()
+            .find(|a| a.path().is_ident("agg"))
+            .map(|a| {
+                aggregate(
+                    &table_p,
+                    &a.parse_args::<Meta>()
+                        .expect("agg should be declared as: agg(func(field))"),
+                )
+            });
+        let table = field
+            .attrs
+            .iter()
+            .find(|a| a.path().is_ident("from"))
+            .map_or(0, |a| {
+                table_index(
+                    &a.parse_args::<Path>()
+                        .expect("from should be declared as: from(Table)"),
+                )
+            });
+        field
+            .attrs
+            .retain(|a| !a.path().is_ident("agg") && !a.path().is_ident("from"));
+        let f = field.ident.to_owned().expect("field sould have a name");
+        match agg {
+            Some(agg) => aggs.push((f, field.ty.to_owned(), agg)),
+            None => fields.push((f, field.ty.to_owned(), table)),
+        }
+    });
+    let grouped = opts.group_by.is_some() || !aggs.is_empty();
+    if grouped && joined {
+        panic!("joined selects should not be grouped nor have #[agg] fields");
+    }
+    let group_by = opts.group_by.unwrap_or_default();
+    if grouped {
+        if let Some((f, _, _)) = fields.iter().find(|(f, _, _)| !group_by.contains(f)) {
+            panic!(
+                "field '{}' should either be listed in group_by or be an #[agg] field",
+                f
+            );
+        }
+    }
 
-    let f_names = idents.iter().map(|f| f.to_string());
+    let f_names = fields.iter().map(|f| f.0.to_string()).collect::<Vec<_>>();
+    let col_mods = fields.iter().map(|f| col_mod(&f.0)).collect::<Vec<_>>();
+    let f_tables = fields.iter().map(|f| &tables[f.2]).collect::<Vec<_>>();
+    let validators = tables
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let (idents, types): (Vec<&Ident>, Vec<&Type>) = fields
+                .iter()
+                .filter(|f| f.2 == i)
+                .map(|f| (&f.0, &f.1))
+                .unzip();
+            quote! {
+                impl #t::SelectTypeValidator for #id {
+                    #(fn #idents(_: #types) {})*
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let agg_names = aggs.iter().map(|a| a.0.to_string()).collect::<Vec<_>>();
+    let agg_checks = aggs
+        .iter()
+        .map(|(_, ty, (agg, count))| match count {
+            true => quote!(let _: #ty = #agg.result().unwrap_or(0);),
+            false => quote!(let _: #ty = #agg.result();),
+        })
+        .collect::<Vec<_>>();
+    let agg_exprs = aggs.iter().map(|a| &a.2 .0).collect::<Vec<_>>();
+    let agg_checks = match aggs.is_empty() {
+        true => quote!(),
+        false => quote! {
+            const _: fn() = || {
+                #(#agg_checks)*
+            };
+        },
+    };
+    let group_mods = group_by.iter().map(col_mod).collect::<Vec<_>>();
+
+    let grouped_payload = match grouped {
+        true => quote! {
+            s["aggs"] = riwaq::serde_json::Value::Array(vec![#({
+                let mut agg = #agg_exprs.to_value();
+                agg["as"] = riwaq::serde_json::Value::from(#agg_names);
+                agg
+            },)*]);
+            s["group_by"] = riwaq::serde_json::json!(([#(#table_p::#group_mods::COLS,)*].concat()));
+            if !self.5.is_empty() {
+                s["having"] = riwaq::serde_json::to_value(&self.5).map_err(riwaq::serde::ser::Error::custom)?;
+            }
+        },
+        false => quote!(),
+    };
+    let (filter_ty, order_ty) = match joined {
+        true => (quote!(#filter_id), quote!(#order_id)),
+        false => (quote!(#table_p::SQLFilter), quote!(#table_p::OrderBy)),
+    };
+    let joined_tys = match joined {
+        true => quote! {
+            /// Filter over any of the joined tables, converted from their `SQLFilter`.
+            pub struct #filter_id(riwaq::sql::FilterItem, riwaq::serde_json::Value);
+            impl riwaq::serde::Serialize for #filter_id {
+                fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                where
+                    S: riwaq::serde::Serializer,
+                {
+                    riwaq::serde::Serialize::serialize(&self.1, serializer)
+                }
+            }
+            impl riwaq::sql::SQLFilterTrait for #filter_id {
+                fn get_filter(&self) -> riwaq::sql::FilterItem {
+                    self.0.clone()
+                }
+            }
+            #(
+                impl From<#tables::SQLFilter> for #filter_id {
+                    fn from(filter: #tables::SQLFilter) -> Self {
+                        let item = riwaq::sql::SQLFilterTrait::get_filter(&filter);
+                        let mut stmt = riwaq::serde_json::to_value(riwaq::sql::FilterStmt::Filter(filter)).unwrap();
+                        #tables::qualify(&mut stmt);
+                        Self(item, stmt["Filter"].take())
+                    }
+                }
+            )*
+
+            /// Sort key over any of the joined tables, converted from their `OrderBy`.
+            #[derive(riwaq::serde::Serialize, Clone)]
+            pub struct #order_id(#table_p::OrderBy);
+            #(
+                impl From<#tables::OrderBy> for #order_id {
+                    fn from(order: #tables::OrderBy) -> Self {
+                        Self(#table_p::OrderBy::from_qualified(order.into_qualified()))
+                    }
+                }
+            )*
+        },
+        false => quote!(),
+    };
+    let joins = opts
+        .joins
+        .iter()
+        .map(|j| {
+            let table = &j.table;
+            let kind = if j.left { "Left" } else { "Inner" };
+            let on = j.on.iter().map(|(l, r)| {
+                let (lt, rt) = (table_of(l), table_of(r));
+                table_index(&lt);
+                table_index(&rt);
+                quote!([#lt::qualified(#l::COL), #rt::qualified(#r::COL)])
+            });
+            quote! {{
+                let mut filter = #table::scope(riwaq::serde_json::Value::Null, #table::Deleted::Exclude);
+                #table::qualify(&mut filter);
+                riwaq::serde_json::json!({
+                    "kind": #kind,
+                    "tbl": #table::T_NAME,
+                    "on": [#(#on,)*],
+                    "filter": filter,
+                })
+            }}
+        })
+        .collect::<Vec<_>>();
+    let db_checks = opts.joins.iter().map(|j| {
+        let table = &j.table;
+        quote_spanned! {table.span()=>
+            const _: () = assert!(
+                #table_p::same_db(#table::DB),
+                "joined tables should be in the same database"
+            );
+        }
+    });
+    let join_checks = opts
+        .joins
+        .iter()
+        .flat_map(|j| j.on.iter())
+        .map(|(l, r)| {
+            let (lt, rt) = (table_of(l), table_of(r));
+            quote_spanned! {r.span()=>
+                const _: fn() = || {
+                    fn join_types<T, A, B>(_: #lt::Column<T, A>, _: #rt::Column<T, B>) {}
+                    join_types(#l, #r);
+                };
+            }
+        })
+        .chain(db_checks)
+        .collect::<Vec<_>>();
+    let joined_payload = match joined {
+        true => quote! {
+            #table_p::qualify(&mut s["filter"]);
+            s["joins"] = riwaq::serde_json::Value::Array(vec![#(#joins,)*]);
+        },
+        false => quote!(),
+    };
+    let read_row = match joined {
+        true => {
+            let idx = fields.iter().map(|f| f.2);
+            quote! {
+                let mut tables = [#(#tables::T_NAME,)*].map(|_| riwaq::serde_json::Map::new());
+                for (key, v) in row {
+                    let col = [#(#tables::T_NAME,)*].iter().enumerate().find_map(|(i, t)| {
+                        key.strip_prefix(t)
+                            .and_then(|col| col.strip_prefix('.'))
+                            .map(|col| (i, col.to_string()))
+                    });
+                    if let Some((i, col)) = col {
+                        tables[i].insert(col, v);
+                    }
+                }
+                #(
+                    if let Some(v) = #f_tables::#col_mods::read(&mut tables[#idx])? {
+                        obj.insert(#f_names.to_string(), v);
+                    }
+                )*
+            }
+        }
+        false => quote! {
+            #(
+                if let Some(v) = #table_p::#col_mods::read(&mut row)? {
+                    obj.insert(#f_names.to_string(), v);
+                }
+            )*
+            #(
+                if let Some(v) = row.remove(#agg_names) {
+                    obj.insert(#agg_names.to_string(), v);
+                }
+            )*
+        },
+    };
+    let cols = match joined {
+        true => quote! {
+            [#(#f_tables::#col_mods::COLS
+                .iter()
+                .map(|col| #f_tables::qualified(col))
+                .collect::<Vec<_>>(),)*]
+                .concat()
+        },
+        false => quote! {
+            [#(#table_p::#col_mods::COLS,)*]
+                .concat()
+                .into_iter()
+                .map(|col| col.to_string())
+                .collect()
+        },
+    };
+    // aggregated columns are taken from the base table, which is all a Column<T, S> can name
+    let typed_aggs = quote! {
+        pub async fn sum<T, S>(&self, col: #table_p::Column<T, S>) -> Result<Option<S>, String>
+        where
+            S: riwaq::serde::de::DeserializeOwned,
+        {
+            self.aggregate(col.sum()).await
+        }
+
+        pub async fn avg<T, S>(&self, col: #table_p::Column<T, S>) -> Result<Option<f64>, String>
+        where
+            S: riwaq::serde::de::DeserializeOwned,
+        {
+            self.aggregate(col.avg()).await
+        }
+
+        pub async fn min<T, S>(&self, col: #table_p::Column<T, S>) -> Result<Option<T>, String>
+        where
+            T: riwaq::serde::de::DeserializeOwned,
+        {
+            self.aggregate(col.min()).await
+        }
+
+        pub async fn max<T, S>(&self, col: #table_p::Column<T, S>) -> Result<Option<T>, String>
+        where
+            T: riwaq::serde::de::DeserializeOwned,
+        {
+            self.aggregate(col.max()).await
+        }
+    };
+    let qualify_agg = match joined {
+        true => quote! {
+            if let Some(col) = agg["col"].as_str().map(#table_p::qualified) {
+                agg["col"] = riwaq::serde_json::Value::from(col);
+            }
+        },
+        false => quote!(),
+    };
+    // joined selects order by the qualified columns, and by the keys of every table, a row of
+    // the base table being repeated for each of its joined rows
+    let (to_order, key_order) = match joined {
+        true => (
+            quote!(o.0.clone()),
+            quote! {
+                [#(#tables::OrderBy::key_order()
+                    .into_iter()
+                    .map(|key| #table_p::OrderBy::from_qualified(key.into_qualified()))
+                    .collect::<Vec<_>>(),)*]
+                    .concat()
+            },
+        ),
+        false => (quote!(o.clone()), quote!(#table_p::OrderBy::key_order())),
+    };
+    let paginate = quote! {
+        /// Up to `n` rows following the `cursor` of the previous page, or the first rows without one.
+        /// Rows are ordered by the query ordering, followed by the primary keys of the selected tables.
+        /// `n` should be at least 1, an empty page could not tell whether more rows follow.
+        pub async fn paginate_after(&self, cursor: Option<&str>, n: u64) -> Result<#page_id, String> {
+            if n == 0 {
+                return Err("paginate_after should be given a page size of at least 1".to_string());
+            }
+            let mut order = self.2.iter().map(|o| #to_order.or_default_nulls()).collect::<Vec<_>>();
+            for key in #key_order {
+                if !order.iter().any(|o| o.col() == key.col()) {
+                    order.push(key.or_default_nulls());
+                }
+            }
+
+            let mut s = riwaq::serde_json::to_value(&self).unwrap();
+            s.as_object_mut().unwrap().remove("offset");
+            s["order_by"] = riwaq::serde_json::to_value(&order).unwrap();
+            s["limit"] = riwaq::serde_json::Value::from(n + 1);
+            for o in order.iter() {
+                let cols = s["cols"].as_array_mut().unwrap();
+                if !cols.iter().any(|c| c.as_str() == Some(o.col())) {
+                    cols.push(riwaq::serde_json::Value::from(o.col()));
+                }
+            }
+            if let Some(cursor) = cursor {
+                let values = (0..cursor.len())
+                    .step_by(2)
+                    .map(|i| cursor.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .and_then(|json| riwaq::serde_json::from_slice::<Vec<riwaq::serde_json::Value>>(&json).ok())
+                    .filter(|values| values.len() == order.len())
+                    .ok_or_else(|| format!("invalid cursor '{}'", cursor))?;
+                let after = match #table_p::OrderBy::after(&order, &values) {
+                    Some(after) => riwaq::serde_json::to_value(after).unwrap(),
+                    None => return Ok(#page_id { rows: vec![], next: None }),
+                };
+                s["filter"] = match s["filter"].take() {
+                    riwaq::serde_json::Value::Null => after,
+                    filter => riwaq::serde_json::json!({ "And": [filter, after] }),
+                };
+            }
+
+            let mut rows = match riwaq::sql::sql_query(s).await? {
+                riwaq::serde_json::Value::Array(rows) => rows,
+                res => return Err(format!("unexpected result {}", res)),
+            };
+            let next = if rows.len() as u64 > n {
+                rows.truncate(n as usize);
+                rows.last().map(|row| {
+                    let values = order.iter().map(|o| row[o.col()].clone()).collect::<Vec<_>>();
+                    riwaq::serde_json::to_string(&values)
+                        .unwrap()
+                        .bytes()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                })
+            } else {
+                None
+            };
+            Ok(#page_id { rows: Self::rows(riwaq::serde_json::Value::Array(rows))?, next })
+        }
+    };
+    let query_fns = match grouped {
+        true => quote! {
+            /// Keeps the groups matching `filter`, on top of the previous ones.
+            pub fn having(mut self, filter: #table_p::Having) -> Self {
+                self.5.push(filter);
+                self
+            }
+        },
+        false => quote! {
+            async fn aggregate<R>(&self, agg: #table_p::Agg<R>) -> Result<Option<R>, String>
+            where
+                R: riwaq::serde::de::DeserializeOwned,
+            {
+                let mut s = riwaq::serde_json::to_value(&self).unwrap();
+                for key in ["order_by", "limit", "offset"] {
+                    s.as_object_mut().unwrap().remove(key);
+                }
+                let mut agg = agg.to_value();
+                agg["as"] = riwaq::serde_json::Value::from("value");
+                #qualify_agg
+                s["cols"] = riwaq::serde_json::json!([]);
+                s["aggs"] = riwaq::serde_json::json!([agg]);
+                let res = riwaq::sql::sql_query(s).await?;
+                riwaq::serde_json::from_value(res[0]["value"].clone()).map_err(|e| e.to_string())
+            }
+
+            pub async fn count(&self) -> Result<i64, String> {
+                self.aggregate(#table_p::count()).await.map(|n| n.unwrap_or(0))
+            }
 
-    TokenStream::from(quote!(
+            pub async fn exists(&self) -> Result<bool, String> {
+                let mut s = riwaq::serde_json::to_value(&self).unwrap();
+                s.as_object_mut().unwrap().remove("offset");
+                s["limit"] = riwaq::serde_json::Value::from(1);
+                riwaq::sql::sql_query(s)
+                    .await
+                    .map(|res| res.as_array().is_some_and(|rows| !rows.is_empty()))
+            }
+
+            #typed_aggs
+
+            #paginate
+        },
+    };
+    let page = match grouped {
+        true => quote!(),
+        false => quote! {
+            /// Rows of a page and the cursor of the next one, `None` on the last page.
+            pub struct #page_id {
+                pub rows: Vec<#id>,
+                pub next: Option<String>,
+            }
+        },
+    };
+
+    quote!(
         #[derive(riwaq::serde::Deserialize)]
         #input
-        impl #table_p::SelectTypeValidator for #id {
-            #(fn #idents(_: #types) {})*
-        }
+        #(#validators)*
+        #agg_checks
+        #(#join_checks)*
+
+        #joined_tys
 
-        #[derive(riwaq::serde::Serialize)]
-        pub struct #impl_id(riwaq::sql::Select<#table_p::SQLFilter>);
+        pub struct #impl_id(
+            riwaq::sql::Select<#filter_ty>,
+            #table_p::Deleted,
+            Vec<#order_ty>,
+            Option<u64>,
+            Option<u64>,
+            Vec<#table_p::Having>,
+        );
+        impl riwaq::serde::Serialize for #impl_id {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: riwaq::serde::Serializer,
+            {
+                let mut s = riwaq::serde_json::to_value(&self.0).map_err(riwaq::serde::ser::Error::custom)?;
+                s["filter"] = #table_p::scope(s["filter"].take(), self.1);
+                if !self.2.is_empty() {
+                    s["order_by"] = riwaq::serde_json::to_value(&self.2).map_err(riwaq::serde::ser::Error::custom)?;
+                }
+                if let Some(limit) = self.3 {
+                    s["limit"] = riwaq::serde_json::Value::from(limit);
+                }
+                if let Some(offset) = self.4 {
+                    s["offset"] = riwaq::serde_json::Value::from(offset);
+                }
+                #grouped_payload
+                #joined_payload
+                riwaq::serde::Serialize::serialize(&#table_p::in_db(s), serializer)
+            }
+        }
         impl std::fmt::Debug for #impl_id {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                f.write_str(&format!("{}", self.0))
+                f.write_str(&riwaq::serde_json::to_string(self).unwrap())
             }
         }
+
+        #page
+
         impl #impl_id {
-            pub fn and(self, filter: #table_p::SQLFilter) -> Self {
-                Self (
-                    riwaq::sql::Select {
-                        filter: Some(match self.0.filter {
-                            Some(ex_filter) => ex_filter.and(filter),
-                            _ => riwaq::sql::FilterStmt::Filter(filter),
-                        }),
-                        ..self.0
-                    }
-                )
+            pub fn and(mut self, filter: impl Into<#filter_ty>) -> Self {
+                let filter = filter.into();
+                self.0.filter = Some(match self.0.filter.take() {
+                    Some(ex_filter) => ex_filter.and(filter),
+                    _ => riwaq::sql::FilterStmt::Filter(filter),
+                });
+                self
             }
-            pub fn and_all<VEC>(self, filter: VEC) -> Self
+            pub fn and_all<VEC>(mut self, filter: VEC) -> Self
             where
-                VEC: IntoIterator<Item = #table_p::SQLFilter>,
+                VEC: IntoIterator<Item = #filter_ty>,
             {
-                Self (
-                    riwaq::sql::Select {
-                        filter: Some(match self.0.filter {
-                            Some(ex_filter) => ex_filter.and_all::<VEC>(filter),
-                            _ => riwaq::sql::FilterStmt::And(
-                                filter
-                                    .into_iter()
-                                    .map(|item| riwaq::sql::FilterStmt::Filter(item))
-                                    .collect(),
-                            ),
-                        }),
-                        ..self.0
-                    }
-                )
+                self.0.filter = Some(match self.0.filter.take() {
+                    Some(ex_filter) => ex_filter.and_all::<VEC>(filter),
+                    _ => riwaq::sql::FilterStmt::And(
+                        filter
+                            .into_iter()
+                            .map(|item| riwaq::sql::FilterStmt::Filter(item))
+                            .collect(),
+                    ),
+                });
+                self
             }
-            pub fn where_(self, filter: #table_p::SQLFilter) -> Self {
+            pub fn where_(self, filter: impl Into<#filter_ty>) -> Self {
                 self.and(filter)
             }
-            pub fn or(self, filter: #table_p::SQLFilter) -> Self {
-                Self (
-                    riwaq::sql::Select {
-                        filter: Some(match self.0.filter {
-                            Some(ex_filter) => ex_filter.or(filter),
-                            _ => riwaq::sql::FilterStmt::Filter(filter),
-                        }),
-                        ..self.0
-                    }
-                )
+            #table_p::deleted_fns!(#table_p::Deleted);
+            /// Orders the rows by `order`, replacing any previous ordering.
+            pub fn order_by(mut self, order: impl Into<#order_ty>) -> Self {
+                self.2 = vec![order.into()];
+                self
             }
-            pub fn or_any<VEC>(self, filter: VEC) -> Self
+            /// Orders rows that are equal on the previous orderings by `order`.
+            pub fn then_by(mut self, order: impl Into<#order_ty>) -> Self {
+                self.2.push(order.into());
+                self
+            }
+            pub fn limit(mut self, n: u64) -> Self {
+                self.3 = Some(n);
+                self
+            }
+            pub fn offset(mut self, n: u64) -> Self {
+                self.4 = Some(n);
+                self
+            }
+            pub fn or(mut self, filter: impl Into<#filter_ty>) -> Self {
+                let filter = filter.into();
+                self.0.filter = Some(match self.0.filter.take() {
+                    Some(ex_filter) => ex_filter.or(filter),
+                    _ => riwaq::sql::FilterStmt::Filter(filter),
+                });
+                self
+            }
+            pub fn or_any<VEC>(mut self, filter: VEC) -> Self
             where
-                VEC: IntoIterator<Item = #table_p::SQLFilter>,
+                VEC: IntoIterator<Item = #filter_ty>,
             {
-                Self (
-                    riwaq::sql::Select {
-                        filter: Some(match self.0.filter {
-                            Some(ex_filter) => ex_filter.or_any::<VEC>(filter),
-                            _ => riwaq::sql::FilterStmt::Or(
-                                filter
-                                    .into_iter()
-                                    .map(|item| riwaq::sql::FilterStmt::Filter(item))
-                                    .collect(),
-                            ),
-                        }),
-                        ..self.0
-                    }
-                )
+                self.0.filter = Some(match self.0.filter.take() {
+                    Some(ex_filter) => ex_filter.or_any::<VEC>(filter),
+                    _ => riwaq::sql::FilterStmt::Or(
+                        filter
+                            .into_iter()
+                            .map(|item| riwaq::sql::FilterStmt::Filter(item))
+                            .collect(),
+                    ),
+                });
+                self
+            }
+
+            fn rows(res: riwaq::serde_json::Value) -> Result<Vec<#id>, String> {
+                let rows = match res {
+                    riwaq::serde_json::Value::Array(rows) => rows
+                        .into_iter()
+                        .map(|row| match row {
+                            riwaq::serde_json::Value::Object(mut row) => {
+                                let mut obj = riwaq::serde_json::Map::new();
+                                #read_row
+                                Ok(riwaq::serde_json::Value::Object(obj))
+                            }
+                            row => Ok(row),
+                        })
+                        .collect::<Result<_, String>>()?,
+                    res => return riwaq::serde_json::from_value(res).map_err(|e| e.to_string()),
+                };
+                riwaq::serde_json::from_value(riwaq::serde_json::Value::Array(rows))
+                    .map_err(|e| e.to_string())
             }
 
             pub async fn exec(&self) -> Result<Vec<#id>, String> {
                 riwaq::sql::sql_query(
                     riwaq::serde_json::to_value(&self).unwrap()
                 ).await
-                .map(|res| riwaq::serde_json::from_value::<Vec<#id>>(res).unwrap())
+                .and_then(Self::rows)
+            }
+
+            async fn fetch(&self, limit: u64) -> Result<Vec<#id>, String> {
+                let mut s = riwaq::serde_json::to_value(&self).unwrap();
+                s["limit"] = riwaq::serde_json::Value::from(limit);
+                riwaq::sql::sql_query(s).await.and_then(Self::rows)
             }
+
+            /// First row of the query, limited to a single row.
+            pub async fn first(&self) -> Result<#id, #table_p::FetchError> {
+                self.fetch(1)
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(#table_p::FetchError::NotFound)
+            }
+
+            /// The single row of the query, an error when there is none or more than one.
+            pub async fn one(&self) -> Result<#id, #table_p::FetchError> {
+                self.one_or_none()
+                    .await?
+                    .ok_or(#table_p::FetchError::NotFound)
+            }
+
+            /// The single row of the query if any, an error when there is more than one.
+            pub async fn one_or_none(&self) -> Result<Option<#id>, #table_p::FetchError> {
+                let mut rows = self.fetch(2).await?;
+                match rows.len() {
+                    0 | 1 => Ok(rows.pop()),
+                    _ => Err(#table_p::FetchError::MultipleRows),
+                }
+            }
+
+            #query_fns
         }
 
         impl #id {
@@ -112,11 +776,267 @@ pub fn select_from(attr: TokenStream, item: TokenStream) -> TokenStream {
                 #impl_id(riwaq::sql::Select {
                     op: Some("Select".to_string()),
                     tbl: #table_p::T_NAME.to_string(),
-                    cols: vec![#(#f_names.to_string() ,)*],
+                    cols: #cols,
                     filter: None
-                })
+                }, #table_p::Deleted::Exclude, vec![], None, None, vec![])
             }
         }
 
-    ))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    fn expansion(item: DeriveInput) -> String {
+        expand(parse_quote!(super::User), item).to_string()
+    }
+
+    fn opts_err(tokens: proc_macro2::TokenStream) -> String {
+        match syn::parse2::<SelectFromOpts>(tokens) {
+            Ok(_) => panic!("select_from options should not parse"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn options() {
+        let opts: SelectFromOpts = parse_quote!(super::User);
+        assert!(opts.group_by.is_none());
+        let opts: SelectFromOpts = parse_quote!(User, group_by(org, kind));
+        assert_eq!(opts.group_by.unwrap(), ["org", "kind"]);
+        assert_eq!(
+            opts_err(quote!(User, order(name))),
+            "expected a single option: group_by(field, ...)"
+        );
+        assert_eq!(
+            opts_err(quote!(User, group_by(org), group_by(kind))),
+            "expected a single option: group_by(field, ...)"
+        );
+    }
+
+    #[test]
+    fn joins() {
+        let opts: SelectFromOpts = parse_quote!(
+            Order join User on User::Id == Order::UserId
+            left join Org on Org::Id == User::OrgId && Org::Region == Order::Region,
+            group_by(id)
+        );
+        assert_eq!(opts.table.to_token_stream().to_string(), "Order");
+        assert_eq!(opts.joins.len(), 2);
+        assert!(!opts.joins[0].left);
+        assert!(opts.joins[1].left);
+        assert_eq!(opts.joins[1].table.to_token_stream().to_string(), "Org");
+        let on = opts.joins[1]
+            .on
+            .iter()
+            .map(|(l, r)| (table_of(l), table_of(r)))
+            .collect::<Vec<_>>();
+        assert!(same_path(&on[0].0, &parse_quote!(Org)));
+        assert!(same_path(&on[0].1, &parse_quote!(User)));
+        assert!(same_path(&on[1].1, &parse_quote!(Order)));
+        assert!(same_path(
+            &table_of(&parse_quote!(super::User::Id)),
+            &parse_quote!(super::User)
+        ));
+        assert!(opts.group_by.is_some());
+        assert_eq!(
+            opts_err(quote!(Order right join User on User::Id == Order::UserId)),
+            "expected 'join' or 'left join'"
+        );
+        assert_eq!(
+            opts_err(quote!(Order join User using User::Id == Order::UserId)),
+            "expected 'on'"
+        );
+        assert_eq!(
+            opts_err(quote!(Order join User on User::Id = Order::UserId)),
+            "expected `==`"
+        );
+    }
+
+    #[test]
+    fn aggregates() {
+        let agg = |meta: Meta| {
+            let (agg, count) = aggregate(&parse_quote!(User), &meta);
+            (agg.to_string(), count)
+        };
+        assert_eq!(
+            agg(parse_quote!(count)),
+            ("User :: count ()".to_string(), true)
+        );
+        assert_eq!(
+            agg(parse_quote!(count(email))),
+            ("User :: Email . count ()".to_string(), true)
+        );
+        assert_eq!(
+            agg(parse_quote!(avg(total_amount))),
+            ("User :: TotalAmount . avg ()".to_string(), false)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "agg should be one of: 'count', 'sum', 'avg', 'min', 'max'")]
+    fn unknown_aggregate() {
+        aggregate(&parse_quote!(User), &parse_quote!(median(age)));
+    }
+
+    #[test]
+    #[should_panic(expected = "agg sum should be given a column: sum(field)")]
+    fn aggregate_without_column() {
+        aggregate(&parse_quote!(User), &parse_quote!(sum));
+    }
+
+    #[test]
+    #[should_panic(expected = "agg max should take a single column")]
+    fn aggregate_of_two_columns() {
+        aggregate(&parse_quote!(User), &parse_quote!(max(a, b)));
+    }
+
+    #[test]
+    fn single_row_fetches() {
+        let out = expansion(parse_quote!(
+            struct UserName {
+                name: String,
+            }
+        ));
+        assert!(out.contains(
+            "pub async fn first (& self) -> Result < UserName , super :: User :: FetchError >"
+        ));
+        assert!(out.contains(
+            "pub async fn one (& self) -> Result < UserName , super :: User :: FetchError >"
+        ));
+        assert!(out.contains(
+            "pub async fn one_or_none (& self) -> Result < Option < UserName > , super :: User :: FetchError >"
+        ));
+        // one_or_none only needs to know whether a second row exists
+        assert!(out.contains("self . fetch (2)"));
+    }
+
+    #[test]
+    fn grouped_select() {
+        let out = expand(
+            parse_quote!(User, group_by(org)),
+            parse_quote!(
+                struct PerOrg {
+                    org: i64,
+                    #[agg(count)]
+                    n: i64,
+                }
+            ),
+        )
+        .to_string();
+        assert!(out.contains("pub fn having"));
+        assert!(out.contains("s [\"group_by\"]"));
+        assert!(!out.contains("# [agg"));
+        assert!(!out.contains("pub async fn count"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "field 'name' should either be listed in group_by or be an #[agg] field"
+    )]
+    fn ungrouped_field() {
+        expand(
+            parse_quote!(User, group_by(org)),
+            parse_quote!(
+                struct PerOrg {
+                    org: i64,
+                    name: String,
+                }
+            ),
+        );
+    }
+
+    #[test]
+    fn pagination() {
+        let out = expansion(parse_quote!(
+            pub struct UserName {
+                name: String,
+            }
+        ));
+        assert!(out.contains("pub struct UserNamePage"));
+        assert!(out.contains("pub fn limit (mut self , n : u64)"));
+        assert!(out.contains("pub fn offset (mut self , n : u64)"));
+        assert!(out.contains("pub async fn paginate_after"));
+        assert!(out.contains("pub async fn count"));
+        assert!(out.contains("pub async fn exists"));
+        assert!(out.contains("pub async fn sum < T , S >"));
+        // an empty page could not tell whether rows follow
+        assert!(out.contains("if n == 0 { return Err"));
+        // the key columns make the cursor position unique
+        assert!(out.contains("super :: User :: OrderBy :: key_order ()"));
+    }
+
+    #[test]
+    fn joined_select() {
+        let out = expand(
+            parse_quote!(Order left join User on User::Id == Order::UserId),
+            parse_quote!(
+                pub struct OrderUser {
+                    id: i64,
+                    #[from(User)]
+                    name: String,
+                }
+            ),
+        )
+        .to_string();
+        assert!(out.contains("pub struct OrderUserFilter"));
+        assert!(out.contains("impl From < User :: SQLFilter > for OrderUserFilter"));
+        assert!(out.contains("pub struct OrderUserOrder"));
+        assert!(out.contains("impl User :: SelectTypeValidator for OrderUser"));
+        assert!(out.contains("\"kind\" : \"Left\""));
+        assert!(out.contains("join_types (User :: Id , Order :: UserId)"));
+        assert!(!out.contains("# [from"));
+        // pages are ordered by the qualified columns and the keys of both tables
+        assert!(out.contains("pub struct OrderUserPage"));
+        assert!(out.contains("pub async fn paginate_after"));
+        assert!(out.contains("pub struct OrderUserOrder (Order :: OrderBy)"));
+        assert!(out.contains("User :: OrderBy :: key_order ()"));
+        assert!(
+            out.contains("pub async fn sum < T , S > (& self , col : Order :: Column < T , S >)")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "table 'User' should be joined only once")]
+    fn table_joined_twice() {
+        expand(
+            parse_quote!(User join User on User::Id == User::ParentId),
+            parse_quote!(
+                struct UserName {
+                    name: String,
+                }
+            ),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "'Org' should be one of the selected tables")]
+    fn field_from_unselected_table() {
+        expand(
+            parse_quote!(Order join User on User::Id == Order::UserId),
+            parse_quote!(
+                struct OrderOrg {
+                    #[from(Org)]
+                    name: String,
+                }
+            ),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "joined selects should not be grouped nor have #[agg] fields")]
+    fn grouped_join() {
+        expand(
+            parse_quote!(Order join User on User::Id == Order::UserId, group_by(id)),
+            parse_quote!(
+                struct OrderUser {
+                    id: i64,
+                }
+            ),
+        );
+    }
 }
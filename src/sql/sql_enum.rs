@@ -0,0 +1,323 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse_macro_input, punctuated::Punctuated, DeriveInput, Expr, ExprLit, ExprUnary, Ident, Lit,
+    Meta, Token, UnOp, Variant,
+};
+
+#[derive(PartialEq, Debug)]
+enum Repr {
+    Str,
+    Enum,
+    Int,
+}
+
+fn enum_opts(attrs: &[syn::Attribute]) -> Vec<Meta> {
+    attrs
+        .iter()
+        .filter(|a| a.path().is_ident("sql_enum"))
+        .flat_map(|a| {
+            a.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+                .expect("invalid sql_enum declaration")
+        })
+        .collect()
+}
+
+fn lit_value(meta: &Meta) -> &Lit {
+    match meta {
+        Meta::NameValue(nv) => match &nv.value {
+            Expr::Lit(ExprLit { lit, .. }) => lit,
+            _ => panic!("sql_enum option value should be a literal"),
+        },
+        _ => panic!("sql_enum option should be given as: key = value"),
+    }
+}
+
+fn discriminant(e: &Expr) -> i64 {
+    match e {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(i), ..
+        }) => i.base10_parse::<i64>().expect("invalid discriminant"),
+        Expr::Unary(ExprUnary {
+            op: UnOp::Neg(_),
+            expr,
+            ..
+        }) => -discriminant(expr),
+        _ => panic!("enum discriminant should be an integer literal"),
+    }
+}
+
+/// `(repr, check)` of the `#[sql_enum(..)]` options.
+fn enum_repr(attrs: &[syn::Attribute]) -> (Repr, bool) {
+    let mut repr = Repr::Str;
+    let mut check = false;
+    for o in enum_opts(attrs) {
+        match o.path().get_ident().map(|id| id.to_string()).as_deref() {
+            Some("repr") => {
+                repr = match lit_value(&o) {
+                    Lit::Str(s) => match s.value().as_str() {
+                        "string" | "varchar" => Repr::Str,
+                        "enum" => Repr::Enum,
+                        "int" | "integer" => Repr::Int,
+                        _ => panic!("sql_enum repr should be one of: 'string', 'enum', 'int'"),
+                    },
+                    _ => panic!("sql_enum repr should be a str literal"),
+                }
+            }
+            Some("check") => check = true,
+            _ => panic!("sql_enum option should be one of: 'repr', 'check'"),
+        }
+    }
+    (repr, check)
+}
+
+/// `(ident, string value, int value)` of each variant, ints following the Rust discriminants.
+fn variant_values(variants: impl IntoIterator<Item = Variant>) -> Vec<(Ident, String, i64)> {
+    let mut next = 0;
+    let values = variants
+        .into_iter()
+        .map(|v| {
+            if !v.fields.is_empty() {
+                panic!("SqlEnum variant '{}' should not have fields", v.ident);
+            }
+            let opts = enum_opts(&v.attrs);
+            let rename =
+                opts.iter()
+                    .find(|o| o.path().is_ident("rename"))
+                    .map(|o| match lit_value(o) {
+                        Lit::Str(s) => s.value(),
+                        _ => panic!("sql_enum rename should be a str literal"),
+                    });
+            let int = v
+                .discriminant
+                .as_ref()
+                .map(|(_, e)| discriminant(e))
+                .unwrap_or(next);
+            next = int + 1;
+            (
+                v.ident.to_owned(),
+                rename.unwrap_or(v.ident.to_string()),
+                int,
+            )
+        })
+        .collect::<Vec<_>>();
+    if values.is_empty() {
+        panic!("SqlEnum should have at least one variant");
+    }
+    values
+}
+
+/// `(SQL type, allowed values)` of the column, the allowed values being empty for `ENUM`.
+fn column_type(repr: &Repr, strs: &[String], ints: &[i64]) -> (String, String) {
+    let quoted = strs
+        .iter()
+        .map(|s| format!("'{}'", s.replace('\'', "''")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    match repr {
+        Repr::Str => (
+            format!(
+                "VARCHAR({})",
+                strs.iter().map(|s| s.chars().count()).max().unwrap()
+            ),
+            quoted,
+        ),
+        Repr::Enum => (format!("ENUM({})", quoted), String::new()),
+        Repr::Int => (
+            "INT".to_string(),
+            ints.iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    }
+}
+
+pub fn sql_enum(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    let name = input.ident;
+
+    let (repr, check) = enum_repr(&input.attrs);
+    let variants = match input.data {
+        syn::Data::Enum(e) => e.variants,
+        _ => panic!("SqlEnum can only be derived for enums"),
+    };
+    let values = variant_values(variants);
+    let idents = values.iter().map(|v| v.0.to_owned()).collect::<Vec<_>>();
+    let strs = values.iter().map(|v| v.1.to_owned()).collect::<Vec<_>>();
+    let ints = values.iter().map(|v| v.2).collect::<Vec<_>>();
+    let (sql_ty, allowed) = column_type(&repr, &strs, &ints);
+    let sql_check = if check && repr != Repr::Enum {
+        quote!(Some(format!("{} IN ({})", col, #allowed)))
+    } else {
+        quote! {
+            let _ = col;
+            None
+        }
+    };
+
+    let (ser, de) = if repr == Repr::Int {
+        (
+            quote! {
+                serializer.serialize_i64(match self {
+                    #(#name::#idents => #ints,)*
+                })
+            },
+            quote! {
+                match <i64 as riwaq::serde::Deserialize>::deserialize(deserializer)? {
+                    #(#ints => Ok(#name::#idents),)*
+                    v => Err(riwaq::serde::de::Error::custom(format!("unknown {} value {}", stringify!(#name), v))),
+                }
+            },
+        )
+    } else {
+        (
+            quote! {
+                serializer.serialize_str(match self {
+                    #(#name::#idents => #strs,)*
+                })
+            },
+            quote! {
+                match <String as riwaq::serde::Deserialize>::deserialize(deserializer)?.as_str() {
+                    #(#strs => Ok(#name::#idents),)*
+                    v => Err(riwaq::serde::de::Error::custom(format!("unknown {} value '{}'", stringify!(#name), v))),
+                }
+            },
+        )
+    };
+
+    TokenStream::from(quote! {
+        impl #name {
+            pub const SQL_TYPE: &'static str = #sql_ty;
+
+            pub fn sql_check(col: &str) -> Option<String> {
+                #sql_check
+            }
+        }
+
+        impl riwaq::serde::Serialize for #name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: riwaq::serde::Serializer,
+            {
+                #ser
+            }
+        }
+
+        impl<'de> riwaq::serde::Deserialize<'de> for #name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: riwaq::serde::Deserializer<'de>,
+            {
+                #de
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::{parse_quote, ItemEnum};
+
+    use super::*;
+
+    fn values(item: ItemEnum) -> Vec<(String, i64)> {
+        variant_values(item.variants)
+            .into_iter()
+            .map(|(_, s, i)| (s, i))
+            .collect()
+    }
+
+    #[test]
+    fn discriminants() {
+        assert_eq!(discriminant(&parse_quote!(7)), 7);
+        assert_eq!(discriminant(&parse_quote!(-3)), -3);
+        let item: ItemEnum = parse_quote! {
+            enum Level {
+                Low,
+                #[sql_enum(rename = "mid")]
+                Medium = 5,
+                High,
+                Lowest = -1,
+                Top,
+            }
+        };
+        assert_eq!(
+            values(item),
+            [
+                ("Low".to_string(), 0),
+                ("mid".to_string(), 5),
+                ("High".to_string(), 6),
+                ("Lowest".to_string(), -1),
+                ("Top".to_string(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "enum discriminant should be an integer literal")]
+    fn const_discriminant() {
+        discriminant(&parse_quote!(A + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "SqlEnum variant 'Named' should not have fields")]
+    fn variant_with_fields() {
+        let item: ItemEnum = parse_quote!(
+            enum E {
+                Named(u8),
+            }
+        );
+        values(item);
+    }
+
+    #[test]
+    fn reprs() {
+        let repr = |attrs: Vec<syn::Attribute>| enum_repr(&attrs);
+        assert_eq!(repr(vec![]), (Repr::Str, false));
+        assert_eq!(
+            repr(vec![parse_quote!(#[sql_enum(repr = "enum")])]),
+            (Repr::Enum, false)
+        );
+        assert_eq!(
+            repr(vec![parse_quote!(#[sql_enum(repr = "integer", check)])]),
+            (Repr::Int, true)
+        );
+        assert_eq!(
+            repr(vec![
+                parse_quote!(#[sql_enum(check)]),
+                parse_quote!(#[doc = ""])
+            ]),
+            (Repr::Str, true)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "sql_enum repr should be one of: 'string', 'enum', 'int'")]
+    fn unknown_repr() {
+        enum_repr(&[parse_quote!(#[sql_enum(repr = "bits")])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "sql_enum option should be one of: 'repr', 'check'")]
+    fn unknown_option() {
+        enum_repr(&[parse_quote!(#[sql_enum(size = 4)])]);
+    }
+
+    #[test]
+    fn column_types() {
+        let strs = ["Active".to_string(), "it's".to_string()];
+        assert_eq!(
+            column_type(&Repr::Str, &strs, &[0, 1]),
+            ("VARCHAR(6)".to_string(), "'Active', 'it''s'".to_string())
+        );
+        assert_eq!(
+            column_type(&Repr::Enum, &strs, &[0, 1]),
+            ("ENUM('Active', 'it''s')".to_string(), String::new())
+        );
+        assert_eq!(
+            column_type(&Repr::Int, &strs, &[0, 5]),
+            ("INT".to_string(), "0, 5".to_string())
+        );
+    }
+}
@@ -1,14 +1,772 @@
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use proc_macro2::{Ident, Span};
-use quote::quote;
+use quote::{format_ident, quote, quote_spanned};
+use riwaq_types::sql::TableDDLOp;
+use serde_json::{json, Value};
 use syn::{
-    parse_macro_input, AngleBracketedGenericArguments, DeriveInput, Expr, ExprAssign, Field, Lit,
-    LitStr, Type, TypePath, Visibility,
+    parse::ParseStream, parse_macro_input, punctuated::Punctuated, spanned::Spanned,
+    AngleBracketedGenericArguments, DeriveInput, Expr, ExprAssign, ExprLit, ExprUnary, Field, Lit,
+    LitStr, Meta, Path, Token, Type, UnOp, Visibility,
 };
-use riwaq_types::sql::{DDLOp, FieldDDL, TableDDL, TableDDLOp};
 
-fn field_to_ddl(f: &Field) -> FieldDDL {
+use super::ddl::{CheckDDL, DDLOp, FieldDDL, ForeignKeyDDL, IndexDDL, RefAction, TableDDL};
+use super::embedded::embedded_mod;
+use super::schema_lock;
+use super::table_opts::TableOpts;
+
+pub(crate) fn has_flag(f: &Field, names: &[&str]) -> bool {
+    f.attrs.iter().any(|a| {
+        a.path()
+            .get_ident()
+            .is_some_and(|id| names.contains(&id.to_string().to_lowercase().as_str()))
+    })
+}
+
+pub(crate) fn option_inner(ty: &Type) -> Option<&Type> {
+    match ty {
+        Type::Path(p) => {
+            let last_p = p.path.segments.last()?;
+            match &last_p.arguments {
+                syn::PathArguments::AngleBracketed(AngleBracketedGenericArguments {
+                    args, ..
+                }) if last_p.ident == "Option" => match args.first() {
+                    Some(syn::GenericArgument::Type(t)) => Some(t),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn ref_action(e: &Expr) -> RefAction {
+    let action = match e {
+        Expr::Path(p) => p.path.get_ident().map(|id| id.to_string().to_lowercase()),
+        _ => None,
+    };
+    match action.as_deref() {
+        Some("no_action") => RefAction::NoAction,
+        Some("restrict") => RefAction::Restrict,
+        Some("cascade") => RefAction::Cascade,
+        Some("set_null") => RefAction::SetNull,
+        Some("set_default") => RefAction::SetDefault,
+        _ => panic!("referential action should be one of: 'no_action', 'restrict', 'cascade', 'set_null', 'set_default'"),
+    }
+}
+
+fn references(f: &Field) -> Option<(Path, ForeignKeyDDL)> {
+    let a = f.attrs.iter().find(|a| {
+        a.path().get_ident().is_some_and(|id| {
+            ["references", "foreign_key"].contains(&id.to_string().to_lowercase().as_str())
+        })
+    })?;
+    let opts = a
+        .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+        .expect("references should be declared as: references(Table::field, on_delete = ...)");
+    let target = match opts.first() {
+        Some(Meta::Path(p)) if p.segments.len() > 1 => p.to_owned(),
+        _ => panic!("references should start with the referenced column path: Table::field"),
+    };
+    let mut fk = ForeignKeyDDL {
+        tbl: target
+            .segments
+            .iter()
+            .rev()
+            .nth(1)
+            .unwrap()
+            .ident
+            .to_string(),
+        col: target.segments.last().unwrap().ident.to_string(),
+        on_delete: RefAction::NoAction,
+        on_update: RefAction::NoAction,
+    };
+    for o in opts.iter().skip(1) {
+        match o {
+            Meta::NameValue(nv) if nv.path.is_ident("on_delete") => {
+                fk.on_delete = ref_action(&nv.value)
+            }
+            Meta::NameValue(nv) if nv.path.is_ident("on_update") => {
+                fk.on_update = ref_action(&nv.value)
+            }
+            _ => panic!("references option should be one of: 'on_delete', 'on_update'"),
+        }
+    }
+    Some((target, fk))
+}
+
+/// Resolves a path written next to the table from `depth` generated modules below it.
+fn nested_path(path: &Path, depth: usize) -> proc_macro2::TokenStream {
+    if path.leading_colon.is_some() || path.segments[0].ident == "crate" {
+        quote!(#path)
+    } else {
+        let supers = (0..depth).map(|_| quote!(super::));
+        quote!(#(#supers)* #path)
+    }
+}
+
+const ENCRYPTED_USAGE: &str =
+    "encrypted should be declared as: #[encrypted] or #[encrypted(deterministic, provider = path::to::Provider)]";
+
+/// `(deterministic, provider)` of an `#[encrypted]` field, the provider defaulting to the
+/// `#[riwaq::key_provider]` impl, which then has to sit at the crate root.
+fn encrypted_opts(f: &Field) -> Option<(bool, Option<Path>)> {
+    let attr = f.attrs.iter().find(|a| a.path().is_ident("encrypted"))?;
+    Some(match &attr.meta {
+        Meta::Path(_) => (false, None),
+        Meta::List(_) => {
+            let (mut deterministic, mut provider) = (false, None);
+            for meta in attr
+                .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+                .unwrap_or_else(|_| panic!("{}", ENCRYPTED_USAGE))
+            {
+                match meta {
+                    Meta::Path(p) if p.is_ident("deterministic") && !deterministic => {
+                        deterministic = true
+                    }
+                    Meta::NameValue(nv) if nv.path.is_ident("provider") && provider.is_none() => {
+                        match nv.value {
+                            Expr::Path(p) => provider = Some(p.path),
+                            _ => panic!("{}", ENCRYPTED_USAGE),
+                        }
+                    }
+                    _ => panic!("{}", ENCRYPTED_USAGE),
+                }
+            }
+            (deterministic, provider)
+        }
+        Meta::NameValue(_) => panic!("{}", ENCRYPTED_USAGE),
+    })
+}
+
+/// `Some(deterministic)` for an `#[encrypted]` or `#[encrypted(deterministic)]` field.
+pub(crate) fn encryption(f: &Field) -> Option<bool> {
+    encrypted_opts(f).map(|(deterministic, _)| deterministic)
+}
+
+/// Column prefix of a `#[flatten]` field, defaults to `<field>_`.
+fn flatten_prefix(f: &Field) -> Option<String> {
+    let attr = f.attrs.iter().find(|a| a.path().is_ident("flatten"))?;
+    Some(match &attr.meta {
+        Meta::Path(_) => format!("{}_", f.ident.as_ref().unwrap()),
+        Meta::List(_) => {
+            let prefix = attr
+                .parse_args::<ExprAssign>()
+                .expect("flatten should be declared as: #[flatten(prefix = \"...\")]");
+            match (*prefix.left, *prefix.right) {
+                (
+                    Expr::Path(p),
+                    Expr::Lit(ExprLit {
+                        lit: Lit::Str(s), ..
+                    }),
+                ) if p.path.is_ident("prefix") => s.value(),
+                _ => panic!("flatten should be declared as: #[flatten(prefix = \"...\")]"),
+            }
+        }
+        Meta::NameValue(_) => {
+            panic!("flatten should be declared as: #[flatten(prefix = \"...\")]")
+        }
+    })
+}
+
+/// Path of the columns module generated by `#[embedded]` for a flattened field type.
+fn embedded_path(ty: &Type) -> Path {
+    match ty {
+        Type::Path(p) if option_inner(ty).is_none() => {
+            let mut path = p.path.clone();
+            let last = path.segments.last_mut().unwrap();
+            last.ident = embedded_mod(&last.ident);
+            path
+        }
+        _ => panic!("flattened field should be a struct marked with #[embedded]"),
+    }
+}
+
+fn default_value(e: &Expr, r_ty: &str) -> Value {
+    match e {
+        Expr::Lit(l) => {
+            match &l.lit {
+                Lit::Str(s) => {
+                    if !STR_TYPES.contains(&r_ty) {
+                        panic!(
+                            "default value of type string literal should only be used on fields of type: '{}'",
+                            STR_TYPES.join("', '")
+                        )
+                    };
+                    Value::from(s.value())
+                }
+                Lit::Char(c) => {
+                    if r_ty != "char" {
+                        panic!("default value of type char should only be used on fields of type: 'char'")
+                    };
+                    Value::from(c.value().to_string())
+                }
+                Lit::Int(i) => {
+                    if ["f32", "f64"].contains(&r_ty) {
+                        Value::from(i.base10_parse::<f64>().expect("invalid default value"))
+                    } else if INT_TYPES.contains(&r_ty) && r_ty.starts_with('u') {
+                        Value::from(i.base10_parse::<u64>().expect("invalid default value"))
+                    } else if INT_TYPES.contains(&r_ty) {
+                        Value::from(i.base10_parse::<i64>().expect("invalid default value"))
+                    } else {
+                        panic!("default value of type int should only be used on integer fields or fields of type: 'f32' or 'f64'")
+                    }
+                }
+                Lit::Float(i) => {
+                    if !["f32", "f64"].contains(&r_ty) {
+                        panic!("default value of type float should only be used on fields of type: 'f32' or 'f64'")
+                    };
+                    Value::from(i.base10_parse::<f64>().expect("invalid default value"))
+                }
+                Lit::Bool(b) => {
+                    if r_ty != "bool" {
+                        panic!("default value of type bool should only be used on fields of type: 'bool'")
+                    };
+                    Value::from(b.value())
+                }
+                _ => panic!("unsupported default value literal"),
+            }
+        }
+        Expr::Unary(ExprUnary {
+            op: UnOp::Neg(_),
+            expr,
+            ..
+        }) if !r_ty.starts_with('u') => match default_value(expr, r_ty) {
+            Value::Number(n) => n
+                .as_i64()
+                .map(|i| Value::from(-i))
+                .unwrap_or_else(|| Value::from(-n.as_f64().unwrap())),
+            _ => panic!("only numeric default values can be negated"),
+        },
+        Expr::Path(p) => json!({
+            "expr": p
+                .path
+                .get_ident()
+                .expect("default SQL expression should be an identifier like CURRENT_TIMESTAMP")
+                .to_string()
+        }),
+        Expr::Assign(ExprAssign { left, right, .. }) => match (&**left, &**right) {
+            (
+                Expr::Path(l),
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(s), ..
+                }),
+            ) if l.path.is_ident("sql") => {
+                json!({ "expr": s.value() })
+            }
+            _ => panic!("default SQL expression should be given as: sql = \"...\""),
+        },
+        _ => panic!("invalid default value"),
+    }
+}
+
+const INDEX_OPTIONS: &str = "'name', 'cols', 'unique', 'renamed_from', 'dropped'";
+
+fn index_to_ddl(
+    meta: &Meta,
+    t_name: &str,
+    unique: bool,
+    field_col: Option<&str>,
+    col_of: &dyn Fn(&str) -> String,
+) -> IndexDDL {
+    let opts = match meta {
+        Meta::Path(_) => vec![],
+        Meta::List(l) => l
+            .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            .expect("invalid index declaration")
+            .into_iter()
+            .collect(),
+        Meta::NameValue(_) => {
+            panic!("index should be declared as: index(name = \"...\", cols(...))")
+        }
+    };
+    let mut seen = vec![];
+    for o in opts.iter() {
+        let key = o
+            .path()
+            .get_ident()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        match key.as_str() {
+            "name" | "renamed_from" | "rename_from" | "unique" | "dropped" | "drop" => {}
+            "cols" if field_col.is_some() => {
+                panic!("field level index should not list cols(...), it indexes its own column")
+            }
+            "cols" => {}
+            _ => panic!(
+                "unknown index option '{}', expected one of: {}",
+                key, INDEX_OPTIONS
+            ),
+        }
+        if seen.contains(&key) {
+            panic!("duplicate index option '{}'", key);
+        }
+        seen.push(key);
+    }
+    let str_opt = |key: &str| {
+        opts.iter().find_map(|o| match o {
+            Meta::NameValue(nv) if nv.path.is_ident(key) => match &nv.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(s), ..
+                }) => Some(s.value()),
+                _ => panic!("index '{}' should be a str literal", key),
+            },
+            Meta::Path(_) | Meta::List(_) if o.path().is_ident(key) => {
+                panic!("index '{}' should be given as: {} = \"...\"", key, key)
+            }
+            _ => None,
+        })
+    };
+    let flag = |key: &str| {
+        opts.iter().any(|o| match o {
+            Meta::Path(p) => p.is_ident(key),
+            _ if o.path().is_ident(key) => panic!("index '{}' does not take a value", key),
+            _ => false,
+        })
+    };
+    let cols = match field_col {
+        Some(col) => vec![col.to_owned()],
+        None => opts
+            .iter()
+            .find_map(|o| match o {
+                Meta::List(l) if l.path.is_ident("cols") => Some(
+                    l.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)
+                        .expect("index cols should be a list of field names")
+                        .into_iter()
+                        .map(|id| col_of(&id.to_string()))
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .expect("table level index should list its columns with cols(...)"),
+    };
+    let unique = unique || flag("unique");
+    let renamed_from = str_opt("renamed_from").or_else(|| str_opt("rename_from"));
+    let dropped = flag("dropped") || flag("drop");
+    let op = match (renamed_from, dropped) {
+        (Some(_), true) => panic!("index should not be both renamed and dropped"),
+        (Some(from), false) => DDLOp::Rename(from),
+        (None, true) => DDLOp::Drop,
+        (None, false) => DDLOp::Keep,
+    };
+    IndexDDL {
+        name: str_opt("name").unwrap_or_else(|| {
+            format!(
+                "{}_{}_{}",
+                t_name,
+                cols.join("_"),
+                if unique { "key" } else { "idx" }
+            )
+        }),
+        cols,
+        unique,
+        op,
+    }
+}
+
+const INT_TYPES: [&str; 8] = ["i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64"];
+const STR_TYPES: [&str; 9] = [
+    "str",
+    "String",
+    "NaiveDate",
+    "NaiveTime",
+    "NaiveDateTime",
+    "DateTime<Utc>",
+    "Uuid",
+    "Decimal",
+    "BigDecimal",
+];
+
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Path(p) => {
+            let last_p = p.path.segments.last().unwrap();
+            match &last_p.arguments {
+                syn::PathArguments::AngleBracketed(AngleBracketedGenericArguments {
+                    args, ..
+                }) => format!(
+                    "{}<{}>",
+                    last_p.ident,
+                    args.iter()
+                        .map(|a| match a {
+                            syn::GenericArgument::Type(t) => type_name(t),
+                            _ => panic!("unsupported type"),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                _ => last_p.ident.to_string(),
+            }
+        }
+        _ => panic!("unsupported type"),
+    }
+}
+
+pub(crate) fn rust_type(ty: &Type) -> (String, bool) {
+    match option_inner(ty) {
+        Some(inner) => (type_name(inner), true),
+        None => (type_name(ty), false),
+    }
+}
+
+fn known_sql_type(r_ty: &str) -> Option<&'static str> {
+    Some(match r_ty {
+        "bool" => "BOOLEAN",
+        "i8" => "TINYINT",
+        "i16" => "SMALLINT",
+        "i32" => "INT",
+        "i64" => "BIGINT",
+        "u8" => "TINYINT UNSIGNED",
+        "u16" => "SMALLINT UNSIGNED",
+        "u32" => "INT UNSIGNED",
+        "u64" => "BIGINT UNSIGNED",
+        "f32" => "FLOAT",
+        "f64" => "DOUBLE",
+        "char" => "VARCHAR(1)",
+        "str" => "VARCHAR(65535)",
+        "String" => "VARCHAR(65535)",
+        "Vec<u8>" => "BLOB",
+        "Value" => "JSON",
+        "NaiveDate" => "DATE",
+        "NaiveTime" => "TIME",
+        "NaiveDateTime" => "DATETIME",
+        "DateTime<Utc>" => "TIMESTAMP",
+        "Uuid" => "CHAR(36)",
+        "Decimal" | "BigDecimal" => "DECIMAL(38, 10)",
+        _ => return None,
+    })
+}
+
+/// Column added by `#[table(soft_delete)]`.
+const DELETED_AT: &str = "deleted_at";
+
+/// Change metadata columns of the `#[table(audited)]` history table.
+const HISTORY_ID: &str = "history_id";
+const HISTORY_OP: &str = "history_op";
+const HISTORY_AT: &str = "history_at";
+const HISTORY_ACTOR: &str = "history_actor";
+
+const TIMESTAMP_TYPES: [&str; 2] = ["DateTime<Utc>", "NaiveDateTime"];
+
+/// `#[created_at]` and `#[updated_at]` columns are stamped by the database with `CURRENT_TIMESTAMP`.
+pub(crate) fn is_timestamp(f: &Field) -> bool {
+    has_flag(f, &["created_at", "updated_at"])
+}
+
+/// Any other plain type is expected to derive `SqlEnum`, its column type is resolved at runtime.
+pub(crate) fn is_sql_enum(r_ty: &str) -> bool {
+    known_sql_type(r_ty).is_none() && !r_ty.contains('<')
+}
+
+/// Type of the sum of a numeric column, `inner` being the column type without `Option`.
+pub(crate) fn sum_type(
+    r_ty: &str,
+    inner: proc_macro2::TokenStream,
+) -> Option<proc_macro2::TokenStream> {
+    Some(match r_ty {
+        "i8" | "i16" | "i32" | "i64" => quote!(i64),
+        "u8" | "u16" | "u32" | "u64" => quote!(u64),
+        "f32" | "f64" => quote!(f64),
+        "Decimal" | "BigDecimal" => inner,
+        _ => return None,
+    })
+}
+
+fn sql_type(r_ty: &str) -> String {
+    match known_sql_type(r_ty) {
+        Some(t) => t.to_string(),
+        None if is_sql_enum(r_ty) => r_ty.to_string(),
+        None => panic!("unsupported type '{}'", r_ty),
+    }
+}
+
+fn compatible_sql_types(r_ty: &str) -> &'static [&'static str] {
+    match r_ty {
+        "bool" => &["BOOLEAN", "BOOL", "BIT", "TINYINT"],
+        "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" => &[
+            "TINYINT",
+            "SMALLINT",
+            "MEDIUMINT",
+            "INT",
+            "INTEGER",
+            "BIGINT",
+            "DECIMAL",
+            "NUMERIC",
+        ],
+        "f32" | "f64" => &["FLOAT", "REAL", "DOUBLE", "DECIMAL", "NUMERIC"],
+        "char" => &["CHAR", "VARCHAR"],
+        "str" | "String" => &[
+            "CHAR",
+            "VARCHAR",
+            "TINYTEXT",
+            "TEXT",
+            "MEDIUMTEXT",
+            "LONGTEXT",
+            "CLOB",
+        ],
+        "Vec<u8>" => &[
+            "BINARY",
+            "VARBINARY",
+            "TINYBLOB",
+            "BLOB",
+            "MEDIUMBLOB",
+            "LONGBLOB",
+            "BYTEA",
+        ],
+        "Value" => &["JSON", "JSONB", "TEXT", "LONGTEXT"],
+        "NaiveDate" => &["DATE"],
+        "NaiveTime" => &["TIME"],
+        "NaiveDateTime" => &["DATETIME", "TIMESTAMP"],
+        "DateTime<Utc>" => &["TIMESTAMP", "TIMESTAMPTZ", "DATETIME"],
+        "Uuid" => &["UUID", "CHAR", "VARCHAR", "BINARY"],
+        "Decimal" | "BigDecimal" => &["DECIMAL", "NUMERIC"],
+        _ => &[],
+    }
+}
+
+fn column_type(f: &Field, r_ty: &str) -> Option<String> {
+    let mut ty = None;
+    let mut set_ty = |t: String| {
+        if ty.replace(t).is_some() {
+            panic!("column type should be overridden only once");
+        }
+    };
+    let int_arg = |a: &syn::Attribute| {
+        a.parse_args::<syn::LitInt>()
+            .and_then(|n| n.base10_parse::<u32>())
+            .expect("column length should be an integer literal")
+    };
+    for a in f.attrs.iter() {
+        let id = match a.path().get_ident() {
+            Some(id) => id.to_string().to_lowercase(),
+            None => continue,
+        };
+        match id.as_str() {
+            "varchar" => set_ty(format!("VARCHAR({})", int_arg(a))),
+            "char" => set_ty(format!("CHAR({})", int_arg(a))),
+            "column" => {
+                let opts = a
+                    .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+                    .expect("invalid column declaration");
+                let mut len = None;
+                let mut precision = None;
+                let mut scale = None;
+                for o in opts.iter() {
+                    let (key, value) = match o {
+                        Meta::NameValue(nv) => (
+                            nv.path
+                                .get_ident()
+                                .map(|id| id.to_string())
+                                .unwrap_or_default(),
+                            match &nv.value {
+                                Expr::Lit(ExprLit { lit, .. }) => lit,
+                                _ => panic!("column options should be literals"),
+                            },
+                        ),
+                        _ => panic!("column options should be given as: key = value"),
+                    };
+                    let int = || match value {
+                        Lit::Int(i) => i.base10_parse::<u32>().expect("invalid integer"),
+                        _ => panic!("column option '{}' should be an integer literal", key),
+                    };
+                    match key.as_str() {
+                        "ty" | "type" => match value {
+                            Lit::Str(s) => set_ty(s.value()),
+                            _ => panic!("column type should be a str literal"),
+                        },
+                        "name" => {}
+                        "len" | "length" => len = Some(int()),
+                        "precision" => precision = Some(int()),
+                        "scale" => scale = Some(int()),
+                        _ => panic!(
+                            "column option should be one of: 'name', 'ty', 'len', 'precision', 'scale'"
+                        ),
+                    }
+                }
+                if let Some(len) = len {
+                    set_ty(match r_ty {
+                        "Vec<u8>" => format!("VARBINARY({})", len),
+                        _ => format!("VARCHAR({})", len),
+                    });
+                }
+                match (precision, scale) {
+                    (Some(p), s) => {
+                        let s = s.unwrap_or(0);
+                        if p == 0 || p > 65 || s > p {
+                            panic!("column precision should be in 1..=65 and scale should not exceed it");
+                        }
+                        set_ty(format!("DECIMAL({}, {})", p, s));
+                    }
+                    (None, Some(_)) => panic!("column scale should be used with precision"),
+                    (None, None) => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let ty = ty?;
+    let base = ty
+        .split(|c: char| c == '(' || c.is_whitespace())
+        .next()
+        .unwrap()
+        .to_uppercase();
+    if !compatible_sql_types(r_ty).contains(&base.as_str()) {
+        panic!(
+            "column type '{}' is not compatible with field type '{}'",
+            ty, r_ty
+        );
+    }
+    if let Some(len) = ty
+        .split_once('(')
+        .and_then(|(_, l)| l.trim_end_matches(')').split(',').next())
+        .and_then(|l| l.trim().parse::<u32>().ok())
+    {
+        if len == 0 || (r_ty == "Uuid" && ["CHAR", "VARCHAR"].contains(&base.as_str()) && len < 36)
+        {
+            panic!(
+                "column type '{}' is too short for field type '{}'",
+                ty, r_ty
+            );
+        }
+    }
+    Some(ty)
+}
+
+fn column_name(f: &Field) -> Option<String> {
+    f.attrs
+        .iter()
+        .filter(|a| a.path().is_ident("column"))
+        .find_map(|a| {
+            a.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+                .expect("invalid column declaration")
+                .into_iter()
+                .find_map(|o| match o {
+                    Meta::NameValue(nv) if nv.path.is_ident("name") => match nv.value {
+                        Expr::Lit(ExprLit {
+                            lit: Lit::Str(s), ..
+                        }) => Some(s.value()),
+                        _ => panic!("column name should be a str literal"),
+                    },
+                    _ => None,
+                })
+        })
+}
+
+fn bound(e: &Expr) -> String {
+    match e {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(i), ..
+        }) => i.base10_digits().to_owned(),
+        Expr::Lit(ExprLit {
+            lit: Lit::Float(f), ..
+        }) => f.base10_digits().to_owned(),
+        Expr::Unary(ExprUnary {
+            op: UnOp::Neg(_),
+            expr,
+            ..
+        }) => format!("-{}", bound(expr)),
+        _ => panic!("range bounds should be numeric literals"),
+    }
+}
+
+fn field_checks(f: &Field, col: &str, r_ty: &str) -> (Vec<String>, proc_macro2::TokenStream) {
+    let mut exprs = vec![];
+    let mut validations = vec![];
+    for a in f.attrs.iter() {
+        let id = match a.path().get_ident() {
+            Some(id) => id.to_string().to_lowercase(),
+            None => continue,
+        };
+        match id.as_str() {
+            "check" => exprs.push(
+                a.parse_args::<LitStr>()
+                    .expect("check should be a SQL expression str literal")
+                    .value(),
+            ),
+            "range" => {
+                if !INT_TYPES.contains(&r_ty) && !["f32", "f64"].contains(&r_ty) {
+                    panic!("range should only be used on numeric fields");
+                }
+                let r = a
+                    .parse_args::<syn::ExprRange>()
+                    .expect("range should be declared as: range(start..=end)");
+                if r.start.is_none() && r.end.is_none() {
+                    panic!(
+                        "range should have at least one bound, e.g. range(0..) or range(..=100)"
+                    );
+                }
+                let desc = quote!(#r).to_string().replace(' ', "");
+                let mut conds = vec![];
+                if let Some(start) = r.start.as_deref().map(bound) {
+                    let v = start.parse::<f64>().unwrap();
+                    conds.push(format!("{} >= {}", col, start));
+                    validations.push(quote!(if n < #v {
+                        return Err(format!("column '{}' value {} is out of range {}", #col, n, #desc));
+                    }));
+                }
+                if let Some(end) = r.end.as_deref().map(bound) {
+                    let v = end.parse::<f64>().unwrap();
+                    match r.limits {
+                        syn::RangeLimits::Closed(_) => {
+                            conds.push(format!("{} <= {}", col, end));
+                            validations.push(quote!(if n > #v {
+                                return Err(format!("column '{}' value {} is out of range {}", #col, n, #desc));
+                            }));
+                        }
+                        syn::RangeLimits::HalfOpen(_) => {
+                            conds.push(format!("{} < {}", col, end));
+                            validations.push(quote!(if n >= #v {
+                                return Err(format!("column '{}' value {} is out of range {}", #col, n, #desc));
+                            }));
+                        }
+                    }
+                }
+                exprs.push(conds.join(" AND "));
+            }
+            "max_len" | "min_len" => {
+                if !["str", "String"].contains(&r_ty) {
+                    panic!(
+                        "{} should only be used on fields of type: 'str' or 'String'",
+                        id
+                    );
+                }
+                let len = a
+                    .parse_args::<syn::LitInt>()
+                    .and_then(|n| n.base10_parse::<usize>())
+                    .expect("length should be an integer literal");
+                if id == "max_len" {
+                    exprs.push(format!("CHAR_LENGTH({}) <= {}", col, len));
+                    validations.push(quote!(if let Some(s) = value.as_str() {
+                        if s.chars().count() > #len {
+                            return Err(format!("column '{}' should be at most {} characters long", #col, #len));
+                        }
+                    }));
+                } else {
+                    exprs.push(format!("CHAR_LENGTH({}) >= {}", col, len));
+                    validations.push(quote!(if let Some(s) = value.as_str() {
+                        if s.chars().count() < #len {
+                            return Err(format!("column '{}' should be at least {} characters long", #col, #len));
+                        }
+                    }));
+                }
+            }
+            _ => {}
+        }
+    }
+    let validations = quote!(#(#validations)*);
+    let validations = if validations.is_empty() {
+        validations
+    } else if INT_TYPES.contains(&r_ty) || ["f32", "f64"].contains(&r_ty) {
+        quote!(if let Some(n) = value.as_f64() { #validations })
+    } else {
+        validations
+    };
+    (exprs, validations)
+}
+
+pub(crate) fn field_to_ddl(f: &Field) -> FieldDDL {
     let rename = f.attrs.iter().find_map(|a| {
         a.path()
             .get_ident()
@@ -28,244 +786,1399 @@ fn field_to_ddl(f: &Field) -> FieldDDL {
             .unwrap_or(None)
     });
 
-    let optional;
-    let r_ty = match &f.ty {
-        syn::Type::Path(p) => {
-            let last_p = &p.path.segments.last().unwrap();
-            if last_p.ident.to_string() == "Option" {
-                optional = true;
-                match &last_p.arguments {
-                    syn::PathArguments::AngleBracketed(AngleBracketedGenericArguments {
-                        args,
-                        ..
-                    }) => match args.first().unwrap() {
-                        syn::GenericArgument::Type(Type::Path(TypePath { path, .. })) => {
-                            path.segments.last().unwrap().ident.to_string()
+    let (r_ty, optional) = rust_type(&f.ty);
+    let timestamp = is_timestamp(f);
+    let version = has_flag(f, &["version"]);
+    if version && (optional || !INT_TYPES.contains(&r_ty.as_str())) {
+        panic!(
+            "version column should be of type: '{}'",
+            INT_TYPES.join("', '")
+        );
+    }
+    if timestamp && (optional || !TIMESTAMP_TYPES.contains(&r_ty.as_str())) {
+        panic!(
+            "created_at/updated_at column should be of type: '{}'",
+            TIMESTAMP_TYPES.join("', '")
+        );
+    }
+    let encrypted = encryption(f).is_some();
+    if encrypted
+        && has_flag(
+            f,
+            &[
+                "primary_key",
+                "pk",
+                "references",
+                "foreign_key",
+                "default",
+                "default_value",
+                "check",
+                "range",
+                "max_len",
+                "min_len",
+                "auto_increment",
+                "autoincrement",
+                "identity",
+                "created_at",
+                "updated_at",
+                "version",
+                "flatten",
+            ],
+        )
+    {
+        panic!(
+            "encrypted column should not be a key, a reference, generated, defaulted or checked"
+        );
+    }
+    // ciphertext is stored as hex text, whatever the plain type
+    let ty = match encrypted {
+        true => "TEXT".to_string(),
+        false => column_type(f, &r_ty).unwrap_or_else(|| match timestamp {
+            true => "TIMESTAMP".to_string(),
+            false => sql_type(&r_ty),
+        }),
+    };
+
+    let retype = f
+        .attrs
+        .iter()
+        .find(|a| {
+            a.path().get_ident().is_some_and(|id| {
+                ["retype_from", "retyped_from"].contains(&id.to_string().to_lowercase().as_str())
+            })
+        })
+        .map(|a| {
+            a.parse_args_with(|input: ParseStream| {
+                let from = input.parse::<Type>()?;
+                let using = if input.parse::<Option<Token![,]>>()?.is_some() {
+                    let key = input.parse::<Ident>()?;
+                    if key != "using" {
+                        return Err(syn::Error::new(key.span(), "expected 'using'"));
+                    }
+                    input.parse::<Token![=]>()?;
+                    Some(input.parse::<LitStr>()?.value())
+                } else {
+                    None
+                };
+                Ok((sql_type(&rust_type(&from).0), using))
+            })
+            .expect("retype should be declared as: retype_from(OldType, using = \"...\")")
+        });
+    if retype.as_ref().is_some_and(|(from, _)| *from == ty) {
+        panic!("retype source type maps to the same column type '{}'", ty);
+    }
+
+    let auto_increment = has_flag(f, &["auto_increment", "autoincrement", "identity"]);
+    if auto_increment && !INT_TYPES.contains(&r_ty.as_str()) {
+        panic!(
+            "auto increment column should be of type: '{}'",
+            INT_TYPES.join("', '")
+        );
+    }
+    if auto_increment && optional {
+        panic!("auto increment column should not be optional");
+    }
+
+    let default = f
+        .attrs
+        .iter()
+        .find(|a| {
+            a.path().get_ident().is_some_and(|id| {
+                ["default", "default_value"].contains(&id.to_string().to_lowercase().as_str())
+            })
+        })
+        .map(|a| {
+            default_value(
+                &a.parse_args::<Expr>().expect("invalid default value"),
+                &r_ty,
+            )
+        })
+        .or_else(|| timestamp.then(|| json!({ "expr": "CURRENT_TIMESTAMP" })))
+        .or_else(|| version.then(|| json!(1)));
+    if default.is_some() && auto_increment {
+        panic!("auto increment column should not have a default value");
+    }
+
+    let drop = f.attrs.iter().find_map(|a| {
+        let id = a.path().get_ident()?.to_string().to_lowercase();
+        if ["dropped_and_data", "drop_and_data"].contains(&id.as_str()) {
+            Some(DDLOp::DropAll)
+        } else if ["dropped", "drop"].contains(&id.as_str()) {
+            Some(DDLOp::Drop)
+        } else {
+            None
+        }
+    });
+    if drop.is_some()
+        && has_flag(
+            f,
+            &[
+                "primary_key",
+                "pk",
+                "index",
+                "unique",
+                "references",
+                "foreign_key",
+            ],
+        )
+    {
+        panic!("dropped column should not be part of a key, an index or a reference");
+    }
+
+    let references = references(f).map(|r| r.1).filter(|_| drop.is_none());
+    if let Some(fk) = &references {
+        if !optional
+            && [&fk.on_delete, &fk.on_update]
+                .iter()
+                .any(|a| matches!(a, RefAction::SetNull))
+        {
+            panic!("referential action 'set_null' should only be used on optional fields");
+        }
+    }
+
+    FieldDDL {
+        name: column_name(f).unwrap_or_else(|| {
+            f.ident
+                .as_ref()
+                .expect("field sould have a name")
+                .to_string()
+        }),
+        opt: optional,
+        ty,
+        default,
+        op: match (drop, retype) {
+            (Some(op), _) => op,
+            (None, Some((from, using))) => DDLOp::Retype {
+                from,
+                using,
+                renamed_from: rename,
+            },
+            (None, None) => rename.map_or(DDLOp::Keep, DDLOp::Rename),
+        },
+        auto_increment,
+        references,
+    }
+}
+
+/// `exec` of a statement builder, audited tables also get `exec_as(actor)`.
+fn exec_fns(
+    ret: proc_macro2::TokenStream,
+    body: proc_macro2::TokenStream,
+    audited: bool,
+) -> proc_macro2::TokenStream {
+    if audited {
+        quote! {
+            pub async fn exec(&self) -> #ret {
+                self.exec_by(None).await
+            }
+            /// Executes the statement, recording `actor` in the history table.
+            pub async fn exec_as(&self, actor: &str) -> #ret {
+                self.exec_by(Some(actor)).await
+            }
+            async fn exec_by(&self, actor: Option<&str>) -> #ret {
+                #body
+            }
+        }
+    } else {
+        quote! {
+            pub async fn exec(&self) -> #ret {
+                #body
+            }
+        }
+    }
+}
+
+/// Filter constructors of a column module, relative to its `COL` const and the table `SQLFilter`.
+///
+/// Encrypted columns only get null checks, plus equality when encrypted deterministically.
+pub(crate) fn column_filters(
+    ty: &proc_macro2::TokenStream,
+    encrypted: Option<bool>,
+) -> proc_macro2::TokenStream {
+    let value = |v: proc_macro2::TokenStream| match encrypted {
+        Some(_) => quote!(super::encrypt(riwaq::serde_json::to_value(#v).unwrap(), true)),
+        None => quote!(riwaq::serde_json::to_value(#v).unwrap()),
+    };
+    let (value_v, start_v, end_v, item_v) = (
+        value(quote!(value)),
+        value(quote!(start)),
+        value(quote!(end)),
+        value(quote!(v)),
+    );
+    let eq_filters = quote! {
+        pub fn eq(value: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Eq{
+                col: COL.to_string(),
+                value: #value_v
+            })
+        }
+        pub fn ne(value: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Ne{
+                col: COL.to_string(),
+                value: #value_v
+            })
+        }
+        pub fn in_<VEC>(values: VEC) -> super::SQLFilter where VEC: IntoIterator<Item = #ty> {
+            super::SQLFilter(riwaq::sql::FilterItem::In{
+                col: COL.to_string(),
+                values: values.into_iter().map(|v| #item_v).collect::<Vec<riwaq::serde_json::Value>>()
+            })
+        }
+        pub fn nin<VEC>(values: VEC) -> super::SQLFilter where VEC: IntoIterator<Item = #ty> {
+            super::SQLFilter(riwaq::sql::FilterItem::Nin{
+                col: COL.to_string(),
+                values: values.into_iter().map(|v| #item_v).collect::<Vec<riwaq::serde_json::Value>>()
+            })
+        }
+    };
+    let range_filters = quote! {
+        pub fn gt(value: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Gt{
+                col: COL.to_string(),
+                value: #value_v
+            })
+        }
+        pub fn gte(value: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Gte{
+                col: COL.to_string(),
+                value: #value_v
+            })
+        }
+        pub fn lt(value: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Lt{
+                col: COL.to_string(),
+                value: #value_v
+            })
+        }
+        pub fn lte(value: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Lte{
+                col: COL.to_string(),
+                value: #value_v
+            })
+        }
+        pub fn between(start: #ty, end: #ty) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Between{
+                col: COL.to_string(),
+                start: #start_v,
+                end: #end_v
+            })
+        }
+        pub fn like(expr: String) -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::Like{
+                col: COL.to_string(),
+                expr
+            })
+        }
+    };
+    let order = quote! {
+        pub fn asc() -> super::OrderBy {
+            super::OrderBy::new(COL, "Asc")
+        }
+        pub fn desc() -> super::OrderBy {
+            super::OrderBy::new(COL, "Desc")
+        }
+    };
+    let (eq_filters, range_filters, order) = match encrypted {
+        None => (eq_filters, range_filters, order),
+        Some(true) => (eq_filters, quote!(), quote!()),
+        Some(false) => (quote!(), quote!(), quote!()),
+    };
+    quote! {
+        pub type Type = #ty;
+
+        #eq_filters
+        #range_filters
+        #order
+        pub fn is_null() -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::IsNull{
+                col: COL.to_string()
+            })
+        }
+        pub fn is_not_null() -> super::SQLFilter {
+            super::SQLFilter(riwaq::sql::FilterItem::IsNotNull{
+                col: COL.to_string()
+            })
+        }
+    }
+}
+
+pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let opts = parse_macro_input!(attr as TableOpts);
+    let tbl_drop = opts.is_drop();
+    let soft_delete = opts.soft_delete;
+    let audited = opts.audited;
+
+    let input = parse_macro_input!(item as DeriveInput);
+    let struct_name = input.ident;
+    let vis = if !tbl_drop {
+        input.vis
+    } else {
+        Visibility::Inherited
+    };
+    let ddl_id = struct_name.to_string().to_case(Case::Snake);
+    let t_name = opts.name.unwrap_or_else(|| ddl_id.to_owned());
+    let t_path = match &opts.schema {
+        Some(schema) => format!("{}.{}", schema, t_name),
+        None => t_name.to_owned(),
+    };
+    let db = opts.db.to_owned();
+
+    let all_fields = match input.data {
+        syn::Data::Struct(s) => s,
+        _ => panic!("struct must have named fields"),
+    }
+    .fields
+    .into_iter()
+    .map(|f| {
+        if flatten_prefix(&f).is_some()
+            && has_flag(
+                &f,
+                &[
+                    "primary_key",
+                    "pk",
+                    "index",
+                    "unique",
+                    "references",
+                    "foreign_key",
+                    "default",
+                    "default_value",
+                    "check",
+                ],
+            )
+        {
+            panic!("flattened field should not be part of a key, an index or a reference, nor have a default or a check");
+        }
+        let mut ddl = field_to_ddl(&f);
+        if tbl_drop {
+            ddl.references = None;
+        }
+        (f, ddl)
+    })
+    .collect::<Vec<(Field, FieldDDL)>>();
+    let fields = all_fields
+        .iter()
+        .filter(|f| !f.1.op.is_drop())
+        .collect::<Vec<_>>();
+
+    let field_names = fields
+        .iter()
+        .filter_map(|field| field.0.ident.as_ref())
+        .collect::<Vec<_>>();
+    let cols = fields
+        .iter()
+        .filter_map(|field| {
+            field
+                .0
+                .ident
+                .as_ref()
+                .map(|f| Ident::new(&f.to_string().to_case(Case::Pascal), Span::call_site()))
+        })
+        .collect::<Vec<_>>();
+    // field types are re-exported through hidden aliases so that they resolve
+    // from inside the generated modules
+    let alias = |f: &Field, inner: bool| {
+        format_ident!(
+            "__riwaq_{}_{}{}",
+            ddl_id,
+            f.ident.as_ref().unwrap(),
+            if inner { "_inner" } else { "" }
+        )
+    };
+    let type_aliases = fields
+        .iter()
+        .flat_map(|(f, _)| {
+            let ty = &f.ty;
+            let id = alias(f, false);
+            let mut v = vec![quote!(type #id = #ty;)];
+            if let Some(inner) = option_inner(ty) {
+                let id = alias(f, true);
+                v.push(quote!(type #id = #inner;));
+            }
+            v
+        })
+        .collect::<Vec<_>>();
+    let field_types = fields
+        .iter()
+        .map(|f| {
+            let id = alias(&f.0, false);
+            quote!(super::#id)
+        })
+        .collect::<Vec<_>>();
+    let nested_types = fields
+        .iter()
+        .map(|f| {
+            let id = alias(&f.0, false);
+            quote!(super::super::#id)
+        })
+        .collect::<Vec<_>>();
+
+    let col_mods = fields
+        .iter()
+        .zip(cols.iter())
+        .zip(nested_types.iter())
+        .map(|(((f, ddl), col), ty)| match flatten_prefix(f) {
+            Some(prefix) => {
+                let embedded = nested_path(&embedded_path(&f.ty), 2);
+                let embedded = quote_spanned!(f.ty.span()=> #embedded);
+                quote! {
+                    pub mod #col {
+                        use super::{OrderBy, SQLFilter};
+                        use #embedded as __embedded;
+
+                        __embedded::column_mods!(#prefix);
+                    }
+                }
+            }
+            None => {
+                let col_name = &ddl.name;
+                let encrypted = encryption(f);
+                let filters = column_filters(ty, encrypted);
+                let read = match encrypted {
+                    Some(_) => quote!(row.remove(COL).map(super::decrypt).transpose()),
+                    None => quote!(Ok(row.remove(COL))),
+                };
+                let column = encrypted.is_none().then(|| {
+                    let inner = alias(f, option_inner(&f.ty).is_some());
+                    let inner = quote!(super::#inner);
+                    let sum =
+                        sum_type(&rust_type(&f.ty).0, inner.clone()).unwrap_or(quote!(NotNumeric));
+                    quote! {
+                        #[allow(non_upper_case_globals)]
+                        pub const #col: Column<#inner, #sum> = Column::new(#col::COL);
+                    }
+                });
+                quote! {
+                    #column
+
+                    pub mod #col {
+                        pub const COL: &str = #col_name;
+                        #[doc(hidden)]
+                        pub const COLS: &[&str] = &[COL];
+
+                        #[doc(hidden)]
+                        pub fn read(
+                            row: &mut riwaq::serde_json::Map<String, riwaq::serde_json::Value>,
+                        ) -> Result<Option<riwaq::serde_json::Value>, String> {
+                            #read
                         }
-                        _ => todo!(),
-                    },
-                    _ => panic!(""),
+
+                        #filters
+                    }
                 }
+            }
+        })
+        .collect::<Vec<_>>();
+    let versions = fields
+        .iter()
+        .filter(|f| has_flag(&f.0, &["version"]))
+        .map(|f| f.1.name.to_owned())
+        .collect::<Vec<_>>();
+    if versions.len() > 1 {
+        panic!(
+            "table '{}' should have at most one #[version] column",
+            struct_name
+        );
+    }
+    let update_sets = fields
+        .iter()
+        .zip(cols.iter())
+        .map(|((f, ddl), col)| {
+            if has_flag(f, &["version"]) {
+                // the expected version is checked by the filter and bumped by the database
+                let col_name = &ddl.name;
+                let bump = format!("{} + 1", col_name);
+                quote!(values.insert(#col_name.to_string(), riwaq::serde_json::json!({ "expr": #bump }));)
+            } else if flatten_prefix(f).is_some() {
+                quote!(values.extend(super::#col::write(riwaq::serde_json::to_value(value).unwrap()));)
+            } else if let Some(deterministic) = encryption(f) {
+                let col_name = &ddl.name;
+                quote!(values.insert(#col_name.to_string(), super::encrypt(riwaq::serde_json::to_value(value).unwrap(), #deterministic));)
             } else {
-                optional = false;
-                last_p.ident.to_string()
+                let col_name = &ddl.name;
+                quote!(values.insert(#col_name.to_string(), riwaq::serde_json::to_value(value).unwrap());)
             }
+        })
+        .collect::<Vec<_>>();
+    let update_filters = fields
+        .iter()
+        .zip(cols.iter())
+        .map(|((f, _), col)| match has_flag(f, &["version"]) {
+            true => quote!(.and(super::#col::eq(value))),
+            false => quote!(),
+        })
+        .collect::<Vec<_>>();
+    let update_doc = match versions.first() {
+        Some(col) => {
+            let doc = format!(
+                " Updates by key should give the expected '{}' with its setter, failing with \
+                 `Error::Conflict` when the row was updated since, other updates bump it unchecked.",
+                col
+            );
+            quote!(#[doc = #doc])
         }
-        _ => panic!("unsupported type"),
+        None => quote!(),
     };
+    // only updates by key expect a version, others bump it unchecked
+    let (update_keyed_ty, update_keyed, update_init, update_by_key) = match versions.is_empty() {
+        true => (quote!(), quote!(), quote!(), quote!()),
+        false => (
+            quote!(, bool),
+            quote!(, self.1),
+            quote!(, false),
+            quote!(, true),
+        ),
+    };
+    let (update_err, update_err_ty, check_version, update_result) = match versions.first() {
+        Some(col) => (
+            quote!(Error),
+            quote! {
+                /// Error of an update on a versioned table.
+                #[derive(Debug, Clone, PartialEq)]
+                pub enum Error {
+                    /// No row matched the expected version, it was updated concurrently.
+                    Conflict,
+                    /// The expected version was not given to an update by key.
+                    MissingVersion,
+                    Sql(String),
+                }
+                impl std::fmt::Display for Error {
+                    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                        match self {
+                            Error::Conflict => write!(f, "version conflict on table '{}'", #t_path),
+                            Error::MissingVersion => write!(f, "update on table '{}' should give the expected '{}'", #t_path, #col),
+                            Error::Sql(e) => f.write_str(e),
+                        }
+                    }
+                }
+                impl std::error::Error for Error {}
+                impl From<String> for Error {
+                    fn from(e: String) -> Self {
+                        Error::Sql(e)
+                    }
+                }
+                impl From<Error> for String {
+                    fn from(e: Error) -> Self {
+                        e.to_string()
+                    }
+                }
+            },
+            quote! {
+                let expected = self.0.values.contains_key(#col);
+                if self.1 && !expected {
+                    return Err(Error::MissingVersion);
+                }
+            },
+            {
+                let bump = format!("{} + 1", col);
+                quote! {
+                    let mut s = s;
+                    if !expected {
+                        s["values"][#col] = riwaq::serde_json::json!({ "expr": #bump });
+                    }
+                    match riwaq::sql::sql_exec(s).await? {
+                        0 if expected => Err(Error::Conflict),
+                        n => Ok(n),
+                    }
+                }
+            },
+        ),
+        None => (
+            quote!(String),
+            quote!(),
+            quote!(),
+            quote!(riwaq::sql::sql_exec(s).await),
+        ),
+    };
+    let mut insert_stmts = fields
+        .iter()
+        .zip(cols.iter())
+        .filter(|(f, _)| flatten_prefix(&f.0).is_some())
+        .map(|(f, col)| {
+            let key = &f.1.name;
+            quote! {
+                if let Some(value) = values.remove(#key) {
+                    values.extend(#col::write(value));
+                }
+            }
+        })
+        .collect::<Vec<_>>();
 
-    let ty = match r_ty.as_str() {
-        "bool" => "BOOLEAN",
-        "i8" => "TINYINT",
-        "i16" => "SMALLINT",
-        "i32" => "INT",
-        "i64" => "BIGINT",
-        "f32" => "FLOAT",
-        "f64" => "DOUBLE",
-        "char" => "VARCHAR(1)",
-        "str" => "VARCHAR(65535)",
-        "String" => "VARCHAR(65535)",
-        t => panic!("unsupported type '{}'", t),
-    }
-    .to_string();
-
-    // let default: Option<Value> = f.attrs.iter().find_map(|a| {
-    //     a.path().get_ident().and_then(|id| {
-    //         if ["default", "default_value"].contains(&id.to_string().as_str()) {
-    //             let r = a
-    //                 .parse_args::<LitStr>()
-    //                 .map(|s| {
-    //                     if !["str", "String"].contains(&r_ty.as_str()) {
-    //                         panic!("default value of type string literal should only be used on fields of type: 'str' or 'String'")
-    //                     };
-    //                     Some(Value::from(s.value()))
-    //                 })
-    //                 .or_else(|_| {
-    //                     a.parse_args::<LitFloat>()
-    //                         .map(|i| {
-    //                             if !["f32", "f64"].contains(&r_ty.as_str()) {
-    //                                 panic!("default value of type float should only be used on fields of type: 'f32' or 'f64'")
-    //                             };
-    //                             Some(Value::from(from_str::<f64>(i.base10_digits()).unwrap()))
-    //                         })
-    //                         .or_else(|_| {
-    //                             a.parse_args::<LitInt>()
-    //                                 .map(|i| {
-    //                                     if !["i8", "i16", "i32", "i64"].contains(&r_ty.as_str()) {
-    //                                         panic!("default value of type int should only be used on fields of type: 'i8', 'i16', 'i32' or 'i64'")
-    //                                     };
-    //                                     Some(Value::from(
-    //                                         from_str::<i64>(i.base10_digits()).unwrap(),
-    //                                     ))
-    //                                 })
-    //                                 .or_else(|_| {
-    //                                     a.parse_args::<LitChar>()
-    //                                         .map(|i| {
-    //                                             if !["char"].contains(&r_ty.as_str()) {
-    //                                                 panic!("default value of type char should only be used on fields of type: 'char'")
-    //                                             };
-    //                                             Some(Value::from(i.value().to_string()))})
-    //                                         .or_else(|_| {
-    //                                             a.parse_args::<LitBool>()
-    //                                                 .map(|i| {
-    //                                                     if !["bool"].contains(&r_ty.as_str()) {
-    //                                                         panic!("default value of type bool should only be used on fields of type: 'bool'")
-    //                                                     };
-    //                                                     Some(Value::from(i.value()))
-    //                                                 })
-    //                                         })
-    //                                 })
-    //                         })
-    //                 }).map_err(|_| a.parse_args::<Path>().unwrap().get_ident().unwrap().to_string()).expect("invalid default value");
-    //             r
-    //         } else {
-    //             None
-    //         }
-    //     })
-    // });
+    for flag in ["created_at", "updated_at"] {
+        if fields.iter().filter(|f| has_flag(&f.0, &[flag])).count() > 1 {
+            panic!(
+                "table '{}' should have at most one #[{}] column",
+                struct_name, flag
+            );
+        }
+    }
+    insert_stmts.extend(fields.iter().filter_map(|(f, ddl)| {
+        let deterministic = encryption(f)?;
+        let col = &ddl.name;
+        Some(quote! {
+            if let Some(value) = values.remove(#col) {
+                values.insert(#col.to_string(), encrypt(value, #deterministic));
+            }
+        })
+    }));
+    let prepare_insert = if insert_stmts.is_empty() {
+        quote!()
+    } else {
+        quote! {
+            let values = match values {
+                riwaq::serde_json::Value::Object(mut values) => {
+                    #(#insert_stmts)*
+                    riwaq::serde_json::Value::Object(values)
+                }
+                values => values,
+            };
+        }
+    };
+    let stamp_update = fields
+        .iter()
+        .filter(|f| has_flag(&f.0, &["updated_at"]))
+        .map(|(_, ddl)| {
+            let col = &ddl.name;
+            quote! {
+                let mut s = s;
+                if s["values"].get(#col).is_none() {
+                    s["values"][#col] = riwaq::serde_json::json!({ "expr": "CURRENT_TIMESTAMP" });
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    if soft_delete && all_fields.iter().any(|f| f.1.name == DELETED_AT) {
+        panic!(
+            "soft delete table '{}' should not declare a '{}' column",
+            struct_name, DELETED_AT
+        );
+    }
+    let providers = fields
+        .iter()
+        .filter_map(|f| encrypted_opts(&f.0))
+        .map(|(_, provider)| match provider {
+            Some(provider) => nested_path(&provider, 1),
+            None => quote!(crate::__RiwaqKeyProvider),
+        })
+        .collect::<Vec<_>>();
+    if providers
+        .iter()
+        .any(|p| p.to_string() != providers[0].to_string())
+    {
+        panic!(
+            "encrypted columns of table '{}' should use the same key provider",
+            struct_name
+        );
+    }
+    let crypto_fns = if let Some(provider) = providers.first() {
+        quote! {
+            type KeyProvider = #provider;
 
-    FieldDDL {
-        name: f
-            .ident
-            .as_ref()
-            .expect("field sould have a name")
-            .to_string(),
-        opt: optional,
-        ty: ty,
-        default: None,
-        op: rename.map_or(DDLOp::Keep, |n| DDLOp::Rename(n)),
+            /// Encrypts a column value with the key provider of the table, as hex text.
+            fn encrypt(value: riwaq::serde_json::Value, deterministic: bool) -> riwaq::serde_json::Value {
+                if value.is_null() {
+                    return value;
+                }
+                let cipher = <KeyProvider as riwaq::sql::KeyProvider>::encrypt(
+                    value.to_string().as_bytes(),
+                    deterministic,
+                );
+                riwaq::serde_json::Value::from(
+                    cipher.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+                )
+            }
+
+            /// Decrypts a column value, an error when it is not a valid ciphertext.
+            fn decrypt(value: riwaq::serde_json::Value) -> Result<riwaq::serde_json::Value, String> {
+                if value.is_null() {
+                    return Ok(value);
+                }
+                let cipher = value
+                    .as_str()
+                    .filter(|hex| hex.len() % 2 == 0)
+                    .and_then(|hex| {
+                        hex.as_bytes()
+                            .chunks(2)
+                            .map(|b| {
+                                std::str::from_utf8(b)
+                                    .ok()
+                                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                            })
+                            .collect::<Option<Vec<u8>>>()
+                    })
+                    .ok_or_else(|| format!("invalid ciphertext in table '{}'", T_NAME))?;
+                let plain = <KeyProvider as riwaq::sql::KeyProvider>::decrypt(&cipher)?;
+                riwaq::serde_json::from_slice(&plain).map_err(|e| e.to_string())
+            }
+        }
+    } else {
+        quote!()
+    };
+    let deleted_fns = match soft_delete {
+        true => quote! {
+            /// Includes soft deleted rows.
+            pub fn with_deleted(mut self) -> Self {
+                self.1 = <$deleted>::Include;
+                self
+            }
+            /// Returns only soft deleted rows.
+            pub fn only_deleted(mut self) -> Self {
+                self.1 = <$deleted>::Only;
+                self
+            }
+        },
+        false => quote!(),
+    };
+
+    let auto_fields = fields
+        .iter()
+        .filter(|f| f.1.auto_increment)
+        .collect::<Vec<_>>();
+    if auto_fields.len() > 1 {
+        panic!(
+            "table '{}' should have at most one auto increment column",
+            struct_name
+        );
     }
-}
+    let insert_fields = fields
+        .iter()
+        .filter(|f| !f.1.auto_increment && !is_timestamp(&f.0) && !has_flag(&f.0, &["version"]))
+        .collect::<Vec<_>>();
+    let insert_decls = insert_fields
+        .iter()
+        .map(|(f, ddl)| {
+            let id = &f.ident;
+            let ty = alias(f, false);
+            let col = &ddl.name;
+            if ddl.default.is_some() {
+                quote! {
+                    #[serde(rename = #col, skip_serializing_if = "Option::is_none")]
+                    pub #id: Option<super::#ty>
+                }
+            } else {
+                quote! {
+                    #[serde(rename = #col)]
+                    pub #id: super::#ty
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut checks = vec![];
+    let mut check_arms = vec![];
+    for (f, ddl) in fields
+        .iter()
+        .filter(|f| flatten_prefix(&f.0).is_none() && encryption(&f.0).is_none())
+    {
+        let (exprs, validations) = field_checks(f, &ddl.name, &rust_type(&f.ty).0);
+        let exprs = exprs.into_iter().filter(|e| !e.trim().is_empty());
+        for (i, expr) in exprs.enumerate() {
+            checks.push(CheckDDL {
+                name: match i {
+                    0 => format!("{}_{}_check", t_name, ddl.name),
+                    i => format!("{}_{}_check{}", t_name, ddl.name, i),
+                },
+                expr,
+            });
+        }
+        if !validations.is_empty() {
+            let col = &ddl.name;
+            check_arms.push(quote!(#col => { #validations }));
+        }
+    }
+    let (check_fns, check_insert, check_update) = if check_arms.is_empty() {
+        (quote!(), quote!(), quote!())
+    } else {
+        (
+            quote! {
+                fn check_value(col: &str, value: &riwaq::serde_json::Value) -> Result<(), String> {
+                    match col {
+                        #(#check_arms)*
+                        _ => {}
+                    }
+                    Ok(())
+                }
+            },
+            quote! {
+                if let riwaq::serde_json::Value::Object(values) = &values {
+                    for (col, value) in values.iter() {
+                        check_value(col, value)?;
+                    }
+                }
+            },
+            quote! {
+                for (col, value) in self.0.values.iter() {
+                    super::check_value(col, value)?;
+                }
+            },
+        )
+    };
+
+    let record_history = |op: &str, nested: bool| {
+        let history = match nested {
+            true => quote!(super::history),
+            false => quote!(history),
+        };
+        match audited {
+            true => quote! {
+                let mut s = s;
+                s["history"] = #history(#op, actor);
+            },
+            false => quote!(),
+        }
+    };
+    let insert_history = record_history("Insert", false);
+    let update_history = record_history("Update", true);
+    let delete_history = record_history("Delete", true);
+
+    let insert_exec = match auto_fields.first() {
+        Some((f, ddl)) => {
+            let ty = alias(f, false);
+            let col = &ddl.name;
+            exec_fns(
+                quote!(Result<super::#ty, String>),
+                quote! {
+                    let values = riwaq::serde_json::to_value(self).unwrap();
+                    #prepare_insert
+                    #check_insert
+                    let mut s = in_db(riwaq::serde_json::json!(riwaq::sql::Insert {
+                        op: Some("Insert".to_string()),
+                        tbl: #t_path.to_string(),
+                        values
+                    }));
+                    s["returning"] = riwaq::serde_json::Value::from(#col);
+                    #insert_history
+                    riwaq::sql::sql_exec(s)
+                        .await
+                        .and_then(|id| <super::#ty>::try_from(id).map_err(|e| e.to_string()))
+                },
+                audited,
+            )
+        }
+        None => exec_fns(
+            quote!(Result<i64, String>),
+            quote! {
+                let values = riwaq::serde_json::to_value(self).unwrap();
+                #prepare_insert
+                #check_insert
+                let s = in_db(riwaq::serde_json::json!(riwaq::sql::Insert {
+                    op: Some("Insert".to_string()),
+                    tbl: #t_path.to_string(),
+                    values
+                }));
+                #insert_history
+                riwaq::sql::sql_exec(s).await
+            },
+            audited,
+        ),
+    };
+
+    let field_pk = fields
+        .iter()
+        .filter(|f| has_flag(&f.0, &["primary_key", "pk"]))
+        .filter_map(|f| f.0.ident.as_ref().map(|id| id.to_string()))
+        .collect::<Vec<_>>();
+    let tbl_pk = opts
+        .primary_key
+        .map(|pk| pk.iter().map(|id| id.to_string()).collect::<Vec<_>>());
+    let pk = match tbl_pk {
+        Some(_) if !field_pk.is_empty() => panic!(
+            "primary key of '{}' should be declared either with #[primary_key] on fields or with #[table(primary_key(...))], not both",
+            struct_name
+        ),
+        Some(pk) => pk,
+        None => field_pk,
+    };
+    if pk.is_empty() && !tbl_drop {
+        panic!(
+            "table '{}' has no primary key, mark a field with #[primary_key] or use #[table(primary_key(...))]",
+            struct_name
+        );
+    }
+    let col_of = |name: &str| {
+        fields
+            .iter()
+            .find(|f| f.0.ident.as_ref().is_some_and(|id| id == name))
+            .map(|f| {
+                if flatten_prefix(&f.0).is_some() {
+                    panic!("flattened field '{}' should not be indexed", name);
+                }
+                f.1.name.to_owned()
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "index column '{}' is not a field of '{}'",
+                    name, struct_name
+                )
+            })
+    };
+    let mut indexes = fields
+        .iter()
+        .flat_map(|(f, ddl)| {
+            f.attrs.iter().filter_map(|a| {
+                let id = a.path().get_ident()?.to_string().to_lowercase();
+                match id.as_str() {
+                    "index" => Some(index_to_ddl(
+                        &a.meta,
+                        &t_name,
+                        false,
+                        Some(&ddl.name),
+                        &col_of,
+                    )),
+                    "unique" => Some(index_to_ddl(
+                        &a.meta,
+                        &t_name,
+                        true,
+                        Some(&ddl.name),
+                        &col_of,
+                    )),
+                    _ => None,
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    indexes.extend(
+        opts.indexes
+            .iter()
+            .map(|(m, unique)| index_to_ddl(m, &t_name, *unique, None, &col_of)),
+    );
+
+    let pk_fields = pk
+        .iter()
+        .map(|k| {
+            let f = fields
+                .iter()
+                .find(|f| f.0.ident.as_ref().is_some_and(|id| id == k))
+                .unwrap_or_else(|| {
+                    panic!(
+                        "primary key column '{}' is not a field of '{}'",
+                        k, struct_name
+                    )
+                });
+            if f.1.opt {
+                panic!("primary key column '{}' should not be optional", k);
+            }
+            if flatten_prefix(&f.0).is_some() {
+                panic!("primary key column '{}' should not be flattened", k);
+            }
+            if encryption(&f.0).is_some() {
+                panic!("primary key column '{}' should not be encrypted", k);
+            }
+            f
+        })
+        .collect::<Vec<_>>();
+    let pk_types = pk_fields
+        .iter()
+        .map(|f| {
+            let id = alias(&f.0, false);
+            quote!(super::#id)
+        })
+        .collect::<Vec<_>>();
+    let pk_cols = pk
+        .iter()
+        .map(|k| Ident::new(&k.to_case(Case::Pascal), Span::call_site()))
+        .collect::<Vec<_>>();
+    let (key_ty, key_filter) = if pk_fields.len() == 1 {
+        let ty = &pk_types[0];
+        let col = &pk_cols[0];
+        (
+            quote!(#ty),
+            quote!(riwaq::sql::FilterStmt::Filter(#col::eq(key))),
+        )
+    } else {
+        let idx = (0..pk_fields.len()).map(syn::Index::from);
+        (
+            quote!((#(#pk_types),*)),
+            quote!(riwaq::sql::FilterStmt::And(vec![
+                #(riwaq::sql::FilterStmt::Filter(#pk_cols::eq(key.#idx)),)*
+            ])),
+        )
+    };
+
+    let table_cols = all_fields
+        .iter()
+        .map(|f| f.1.to_owned())
+        .chain(soft_delete.then(|| FieldDDL {
+            name: DELETED_AT.to_string(),
+            opt: true,
+            ty: "TIMESTAMP".to_string(),
+            default: None,
+            op: DDLOp::Keep,
+            auto_increment: false,
+            references: None,
+        }))
+        .collect::<Vec<FieldDDL>>();
+    let t_op = opts.op.unwrap_or(TableDDLOp::Keep);
+
+    // the history table keeps every column, unconstrained, followed by the change metadata
+    let history_name = format!("{}_history", t_name);
+    let history_path = format!("{}_history", t_path);
+    let history_json = audited.then(|| {
+        let meta_col = |name: &str, opt: bool, ty: &str| FieldDDL {
+            name: name.to_string(),
+            opt,
+            ty: ty.to_string(),
+            default: None,
+            op: DDLOp::Keep,
+            auto_increment: false,
+            references: None,
+        };
+        let mut cols = table_cols
+            .iter()
+            .map(|c| FieldDDL {
+                opt: true,
+                default: None,
+                auto_increment: false,
+                references: None,
+                ..c.to_owned()
+            })
+            .collect::<Vec<_>>();
+        cols.extend([
+            FieldDDL {
+                auto_increment: true,
+                ..meta_col(HISTORY_ID, false, "BIGINT")
+            },
+            meta_col(HISTORY_OP, false, "VARCHAR(6)"),
+            FieldDDL {
+                default: Some(json!({ "expr": "CURRENT_TIMESTAMP" })),
+                ..meta_col(HISTORY_AT, false, "TIMESTAMP")
+            },
+            meta_col(HISTORY_ACTOR, true, "VARCHAR(255)"),
+        ]);
+        serde_json::to_string(&TableDDL {
+            name: history_name.to_owned(),
+            cols,
+            op: match &t_op {
+                TableDDLOp::Rename(from) => TableDDLOp::Rename(format!("{}_history", from)),
+                op => op.to_owned(),
+            },
+            schema: opts.schema.to_owned(),
+            db: db.to_owned(),
+            pk: vec![HISTORY_ID.to_string()],
+            indexes: vec![],
+            checks: vec![],
+        })
+        .unwrap()
+    });
+    if audited
+        && table_cols
+            .iter()
+            .any(|c| [HISTORY_ID, HISTORY_OP, HISTORY_AT, HISTORY_ACTOR].contains(&c.name.as_str()))
+    {
+        panic!(
+            "audited table '{}' should not declare a '{}*' column",
+            struct_name, "history_"
+        );
+    }
+
+    let ddl_json = serde_json::to_string(&TableDDL {
+        name: t_name.to_owned(),
+        cols: table_cols,
+        op: t_op,
+        schema: opts.schema,
+        db: db.to_owned(),
+        pk: pk_fields.iter().map(|f| f.1.name.to_owned()).collect(),
+        indexes,
+        checks,
+    })
+    .unwrap();
+
+    let mut type_checks = vec![];
+    let mut ddl_patches = vec![];
+    let mut history_patches = vec![];
+    for (f, ddl) in fields.iter().filter(|_| !tbl_drop) {
+        let i = all_fields
+            .iter()
+            .position(|a| a.0.ident == f.ident)
+            .unwrap();
+        let ty = alias(f, option_inner(&f.ty).is_some());
+        let ty = quote_spanned!(f.ty.span()=> super::#ty);
+        if let Some((target, _)) = references(f) {
+            let mut tbl = target.clone();
+            let col = tbl.segments.pop().unwrap().into_value().ident;
+            tbl.segments.pop_punct();
+            let tbl = nested_path(&tbl, 1);
+            let col_mod = Ident::new(&col.to_string().to_case(Case::Pascal), col.span());
+            type_checks.push(quote_spanned! {target.span()=>
+                const _: fn(#tbl::#col_mod::Type) -> #ty = std::convert::identity::<#ty>;
+            });
+            ddl_patches.push(quote! {
+                ddl["cols"][#i]["references"]["tbl"] = riwaq::serde_json::Value::from(#tbl::T_NAME);
+                ddl["cols"][#i]["references"]["col"] = riwaq::serde_json::Value::from(#tbl::#col_mod::COL);
+            });
+        }
+        if is_sql_enum(&rust_type(&f.ty).0)
+            && flatten_prefix(f).is_none()
+            && encryption(f).is_none()
+        {
+            let col = &ddl.name;
+            let check_name = format!("{}_{}_enum", t_name, col);
+            type_checks.push(quote_spanned! {f.ty.span()=>
+                const _: &str = <#ty>::SQL_TYPE;
+            });
+            history_patches.push(quote! {
+                ddl["cols"][#i]["ty"] = riwaq::serde_json::Value::from(<#ty>::SQL_TYPE);
+            });
+            ddl_patches.push(quote! {
+                ddl["cols"][#i]["ty"] = riwaq::serde_json::Value::from(<#ty>::SQL_TYPE);
+                if let Some(expr) = <#ty>::sql_check(#col) {
+                    ddl["checks"].as_array_mut().unwrap().push(riwaq::serde_json::json!({
+                        "name": #check_name,
+                        "expr": expr
+                    }));
+                }
+            });
+        }
+    }
+    // flattened columns are spliced last, from the end, so the indexes above stay valid
+    for (i, (f, ddl)) in all_fields.iter().enumerate().rev() {
+        if let Some(prefix) = flatten_prefix(f).filter(|_| !tbl_drop) {
+            let embedded = nested_path(&embedded_path(&f.ty), 1);
+            let mut cols = quote!(#embedded::columns(#prefix));
+            if ddl.op.is_drop() {
+                cols = quote! {
+                    #cols.into_iter().map(|mut col| {
+                        col["op"] = ddl["cols"][#i]["op"].clone();
+                        col
+                    }).collect::<Vec<_>>()
+                };
+            }
+            let splice = quote! {
+                let cols = #cols;
+                ddl["cols"].as_array_mut().unwrap().splice(#i..#i + 1, cols);
+            };
+            history_patches.push(splice.clone());
+            ddl_patches.push(splice);
+        }
+    }
+    let ddl_ptr = |json: &str, patches: &[proc_macro2::TokenStream]| {
+        if patches.is_empty() {
+            let ddl = format!("{}\0", json);
+            quote!(#ddl.as_ptr())
+        } else {
+            quote! {
+                static DDL: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+                DDL.get_or_init(|| {
+                    let mut ddl = riwaq::serde_json::from_str::<riwaq::serde_json::Value>(#json).unwrap();
+                    #(#patches)*
+                    format!("{}\0", ddl)
+                })
+                .as_ptr()
+            }
+        }
+    };
+    let history_export = match &history_json {
+        Some(json) => {
+            let history_ddl_name = format_ident!("riwaq_table_ddl_{}_history", ddl_id);
+            let ptr = ddl_ptr(json, &history_patches);
+            quote! {
+                #[no_mangle]
+                extern "C" fn #history_ddl_name() -> *const u8 {
+                    #ptr
+                }
+            }
+        }
+        None => quote!(),
+    };
+    let ddl_ptr = ddl_ptr(&ddl_json, &ddl_patches);
+    let history_fn = if audited {
+        let deleted_cols = match soft_delete {
+            true => quote!(&[#DELETED_AT]),
+            false => quote!(),
+        };
+        quote! {
+            /// History row to record along with a statement, the host copies `cols` of the affected rows.
+            fn history(op: &str, actor: Option<&str>) -> riwaq::serde_json::Value {
+                riwaq::serde_json::json!({
+                    "tbl": #history_path,
+                    "op": op,
+                    "actor": actor,
+                    "cols": ([#(#cols::COLS,)* #deleted_cols].concat()),
+                })
+            }
+        }
+    } else {
+        quote!()
+    };
 
-pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let tbl_drop = syn::parse::<Ident>(attr.to_owned())
-        .and_then(|t| {
-            Ok(
-                if ["dropped_table_and_data", "drop_table_and_data"]
-                    .contains(&t.to_string().to_lowercase().as_str())
-                {
-                    Some(TableDDLOp::DropAll)
-                } else if ["dropped", "drop"].contains(&t.to_string().to_lowercase().as_str()) {
-                    Some(TableDDLOp::Drop)
-                } else {
-                    None
+    let (scope, delete_force_ty, delete_force, delete_init, delete_force_fn, soft_delete_exec) =
+        if soft_delete {
+            (
+                quote! {
+                    let item = match deleted {
+                        Deleted::Exclude => riwaq::sql::FilterItem::IsNull { col: #DELETED_AT.to_string() },
+                        Deleted::Only => riwaq::sql::FilterItem::IsNotNull { col: #DELETED_AT.to_string() },
+                        Deleted::Include => return filter,
+                    };
+                    let item = riwaq::serde_json::to_value(riwaq::sql::FilterStmt::Filter(SQLFilter(item))).unwrap();
+                    match filter {
+                        riwaq::serde_json::Value::Null => item,
+                        filter => riwaq::serde_json::json!({ "And": [item, filter] }),
+                    }
                 },
-            )
-        })
-        .unwrap_or(None);
-    let tbl_undrop = syn::parse::<Ident>(attr.to_owned())
-        .and_then(|t| {
-            Ok(
-                if ["undropped", "undrop"].contains(&t.to_string().to_lowercase().as_str()) {
-                    Some(TableDDLOp::Undrop)
-                } else {
-                    None
+                quote!(, bool),
+                quote!(, self.1),
+                quote!(, false),
+                quote! {
+                    /// Deletes the rows for good instead of setting `deleted_at`.
+                    pub fn force(self) -> Self {
+                        Self(self.0, true)
+                    }
+                },
+                quote! {
+                    let mut s = s;
+                    if !self.1 {
+                        s["op"] = riwaq::serde_json::Value::from("Update");
+                        s["values"] = riwaq::serde_json::json!({ #DELETED_AT: { "expr": "CURRENT_TIMESTAMP" } });
+                        s["filter"] = super::scope(s["filter"].take(), super::Deleted::Exclude);
+                    }
                 },
             )
-        })
-        .unwrap_or(None);
-    let tbl_rename_from = syn::parse::<ExprAssign>(attr)
-        .and_then(|t| {
-            let l = match *t.left {
-                Expr::Path(p) => p.path.get_ident().unwrap().to_string(),
-                _ => "".to_owned(),
-            };
-            let r = match *t.right {
-                Expr::Lit(l) => match l.lit {
-                    Lit::Str(s) => s.value(),
-                    _ => panic!("table rename source should be str literal"),
+        } else {
+            (
+                quote! {
+                    let _ = deleted;
+                    filter
                 },
-                _ => panic!("table rename source should be str literal"),
-            };
-            if ["renamed_from", "rename_from"].contains(&l.to_lowercase().as_str()) {
-                Ok(Some(r))
-            } else {
-                panic!("table attribute should be one of: 'renamed_from', 'rename_from'")
-            }
-        })
-        .unwrap_or(None);
-
-    let input = parse_macro_input!(item as DeriveInput);
-    let struct_name = input.ident;
-    let vis = if let None = tbl_drop {
-        input.vis
+                quote!(),
+                quote!(),
+                quote!(),
+                quote!(),
+                quote!(),
+            )
+        };
+    let (restore_fn, restore_ctor) = if soft_delete {
+        (
+            quote! {
+                /// Clears `deleted_at` of the soft deleted rows.
+                pub fn restore(self) -> Update {
+                    let mut values = self.0.values;
+                    values.insert(#DELETED_AT.to_string(), riwaq::serde_json::Value::Null);
+                    Update(riwaq::sql::Update {
+                        values,
+                        ..self.0
+                    } #update_keyed)
+                }
+            },
+            quote! {
+                pub fn restore() -> Update {
+                    Update(riwaq::sql::Update {
+                        op: Some("Update".to_string()),
+                        tbl: #t_path.to_string(),
+                        values: std::collections::HashMap::from([(#DELETED_AT.to_string(), riwaq::serde_json::Value::Null)]),
+                        filter: None
+                    } #update_init)
+                }
+            },
+        )
     } else {
-        Visibility::Inherited
+        (quote!(), quote!())
     };
-    let t_name = struct_name.to_string().to_case(Case::Snake);
 
-    let fields = match input.data {
-        syn::Data::Struct(s) => s,
-        _ => panic!("struct must have named fields"),
-    }
-    .fields
-    .into_iter()
-    .map(|f| {
-        let ddl = field_to_ddl(&f);
-        (f, ddl)
-    })
-    .collect::<Vec<(Field, FieldDDL)>>();
+    let update_exec = exec_fns(
+        quote!(Result<i64, #update_err>),
+        quote! {
+            #check_version
+            #check_update
+            let s = super::in_db(riwaq::serde_json::json!(self.0));
+            #(#stamp_update)*
+            #update_history
+            #update_result
+        },
+        audited,
+    );
+    let delete_exec = exec_fns(
+        quote!(Result<i64, String>),
+        quote! {
+            let s = super::in_db(riwaq::serde_json::json!(self.0));
+            #soft_delete_exec
+            #delete_history
+            riwaq::sql::sql_exec(s).await
+        },
+        audited,
+    );
 
-    let field_names = fields
-        .iter()
-        .filter_map(|field| field.0.ident.as_ref())
-        .collect::<Vec<_>>();
-    let field_names_str = fields
-        .iter()
-        .filter_map(|field| field.0.ident.as_ref().map(|f| f.to_string()))
-        .collect::<Vec<_>>();
-    let cols = fields
+    let db_const = match &db {
+        Some(db) => quote!(Some(#db)),
+        None => quote!(None),
+    };
+    // tables of other databases are locked apart, as they may share names
+    let lock_key = |path: &str| match &db {
+        Some(db) => format!("{}:{}", db, path),
+        None => path.to_string(),
+    };
+    let (t_key, history_key) = (lock_key(&t_path), lock_key(&history_path));
+    let mut locked = vec![(t_key.as_str(), ddl_json.as_str())];
+    if let Some(json) = &history_json {
+        locked.push((history_key.as_str(), json.as_str()));
+    }
+    let sql_enums = fields
         .iter()
-        .filter_map(|field| {
-            field
-                .0
-                .ident
-                .as_ref()
-                .map(|f| Ident::new(&f.to_string().to_case(Case::Pascal), Span::call_site()))
+        .filter(|(f, _)| {
+            is_sql_enum(&rust_type(&f.ty).0)
+                && flatten_prefix(f).is_none()
+                && encryption(f).is_none()
         })
+        .map(|(_, ddl)| ddl.name.as_str())
         .collect::<Vec<_>>();
-    let field_types = fields.iter().map(|field| &field.0.ty).collect::<Vec<_>>();
-
-    let ddl = format!(
-        "{}\0",
-        serde_json::to_string(&TableDDL {
-            name: t_name.to_owned(),
-            cols: fields
-                .iter()
-                .map(|f| f.1.to_owned())
-                .collect::<Vec<FieldDDL>>(),
-            op: if let Some(op) = &tbl_drop {
-                op.to_owned()
-            } else if let Some(op) = &tbl_undrop {
-                op.to_owned()
-            } else if let Some(rename_src) = tbl_rename_from {
-                TableDDLOp::Rename(rename_src)
-            } else {
-                TableDDLOp::Keep
-            }
-        })
-        .unwrap()
-    );
+    let schema_lock = schema_lock::check(&locked, &sql_enums, struct_name.span());
 
     let ddl_name = Ident::new(
-        format!("riwaq_table_ddl_{}", t_name).as_str(),
+        format!("riwaq_table_ddl_{}", ddl_id).as_str(),
         Span::call_site(),
     );
 
-    let output = if let Some(_) = tbl_drop {
+    let output = if tbl_drop {
         quote! {
             mod #struct_name {
                 #[no_mangle]
                 extern "C" fn #ddl_name() -> *const u8 {
-                    #ddl.as_ptr()
+                    #ddl_ptr
                 }
+
+                #history_export
             }
         }
     } else {
         quote! {
+            #(
+                #[doc(hidden)]
+                #[allow(non_camel_case_types)]
+                #type_aliases
+            )*
+
             #[allow(non_snake_case)]
             #vis mod #struct_name {
-                pub const T_NAME: &'static str = #t_name;
+                pub const T_NAME: &'static str = #t_path;
+                /// Database holding the table, `None` for the default one.
+                pub const DB: Option<&'static str> = #db_const;
+
+                /// Statement run against the database of the table.
+                #[doc(hidden)]
+                pub fn in_db(mut s: riwaq::serde_json::Value) -> riwaq::serde_json::Value {
+                    if let Some(db) = DB {
+                        s["db"] = riwaq::serde_json::Value::from(db);
+                    }
+                    s
+                }
+
+                /// Whether `db` is the database of the table, for selects joining tables.
+                #[doc(hidden)]
+                pub const fn same_db(db: Option<&str>) -> bool {
+                    let (a, b) = match (DB, db) {
+                        (Some(a), Some(b)) => (a.as_bytes(), b.as_bytes()),
+                        (a, b) => return a.is_none() && b.is_none(),
+                    };
+                    if a.len() != b.len() {
+                        return false;
+                    }
+                    let mut i = 0;
+                    while i < a.len() {
+                        if a[i] != b[i] {
+                            return false;
+                        }
+                        i += 1;
+                    }
+                    true
+                }
 
                 pub trait SelectTypeValidator {
                     #(fn #field_names(_: #field_types) {} )*
@@ -284,109 +2197,376 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                     }
                 }
 
-                #(
-                    pub mod #cols {
-                        pub fn eq(value: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Eq{
-                                col: #field_names_str.to_string(),
-                                value: riwaq::serde_json::to_value(value).unwrap()
-                            })
-                        }
-                        pub fn ne(value: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Ne{
-                                col: #field_names_str.to_string(),
-                                value: riwaq::serde_json::to_value(value).unwrap()
-                            })
-                        }
-                        pub fn in_<VEC>(values: VEC) -> super::SQLFilter where VEC: IntoIterator<Item = #field_types> {
-                            super::SQLFilter(riwaq::sql::FilterItem::In{
-                                col: #field_names_str.to_string(),
-                                values: values.into_iter().map(|v| riwaq::serde_json::to_value(v).unwrap()).collect::<Vec<riwaq::serde_json::Value>>()
-                            })
-                        }
-                        pub fn nin<VEC>(values: VEC) -> super::SQLFilter where VEC: IntoIterator<Item = #field_types> {
-                            super::SQLFilter(riwaq::sql::FilterItem::Nin{
-                                col: #field_names_str.to_string(),
-                                values: values.into_iter().map(|v| riwaq::serde_json::to_value(v).unwrap()).collect::<Vec<riwaq::serde_json::Value>>()
-                            })
-                        }
-                        pub fn gt(value: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Gt{
-                                col: #field_names_str.to_string(),
-                                value: riwaq::serde_json::to_value(value).unwrap()
-                            })
-                        }
-                        pub fn gte(value: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Gte{
-                                col: #field_names_str.to_string(),
-                                value: riwaq::serde_json::to_value(value).unwrap()
-                            })
+                /// Typed column value, e.g. `Orders::Amount`, taking the aggregates of the column.
+                /// `S` is the type of its sum, `NotNumeric` when it cannot be summed.
+                pub struct Column<T, S = NotNumeric>(&'static str, std::marker::PhantomData<(T, S)>);
+                impl<T, S> Clone for Column<T, S> {
+                    fn clone(&self) -> Self {
+                        *self
+                    }
+                }
+                impl<T, S> Copy for Column<T, S> {}
+                impl<T, S> Column<T, S> {
+                    #[doc(hidden)]
+                    pub const fn new(col: &'static str) -> Self {
+                        Self(col, std::marker::PhantomData)
+                    }
+                    /// Count of the non null values.
+                    pub fn count(self) -> Agg<i64> {
+                        Agg::new("Count", Some(self.0))
+                    }
+                    pub fn min(self) -> Agg<T> {
+                        Agg::new("Min", Some(self.0))
+                    }
+                    pub fn max(self) -> Agg<T> {
+                        Agg::new("Max", Some(self.0))
+                    }
+                    pub fn sum(self) -> Agg<S>
+                    where
+                        S: riwaq::serde::de::DeserializeOwned,
+                    {
+                        Agg::new("Sum", Some(self.0))
+                    }
+                    pub fn avg(self) -> Agg<f64>
+                    where
+                        S: riwaq::serde::de::DeserializeOwned,
+                    {
+                        Agg::new("Avg", Some(self.0))
+                    }
+                }
+
+                /// Sum type of the columns that are not numeric.
+                pub enum NotNumeric {}
+
+                /// Aggregate over the rows of the table, `R` is the type of its value.
+                pub struct Agg<R>(&'static str, Option<&'static str>, std::marker::PhantomData<R>);
+                impl<R> Agg<R> {
+                    fn new(func: &'static str, col: Option<&'static str>) -> Self {
+                        Self(func, col, std::marker::PhantomData)
+                    }
+                    #[doc(hidden)]
+                    pub fn to_value(&self) -> riwaq::serde_json::Value {
+                        riwaq::serde_json::json!({ "func": self.0, "col": self.1 })
+                    }
+                    /// Value type of the aggregate, for type checking projections.
+                    #[doc(hidden)]
+                    pub fn result(&self) -> Option<R> {
+                        None
+                    }
+                    fn having(&self, op: &str, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        Having(riwaq::serde_json::json!({
+                            "agg": self.to_value(),
+                            "op": op,
+                            "value": value,
+                        }))
+                    }
+                    pub fn eq(self, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        self.having("Eq", value)
+                    }
+                    pub fn ne(self, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        self.having("Ne", value)
+                    }
+                    pub fn gt(self, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        self.having("Gt", value)
+                    }
+                    pub fn gte(self, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        self.having("Gte", value)
+                    }
+                    pub fn lt(self, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        self.having("Lt", value)
+                    }
+                    pub fn lte(self, value: R) -> Having
+                    where
+                        R: riwaq::serde::Serialize,
+                    {
+                        self.having("Lte", value)
+                    }
+                }
+
+                /// `HAVING` filter of a grouped select, built from an `Agg`.
+                #[derive(riwaq::serde::Serialize)]
+                pub struct Having(riwaq::serde_json::Value);
+
+                /// Column name prefixed with the table name, for selects joining tables.
+                #[doc(hidden)]
+                pub fn qualified(col: &str) -> String {
+                    format!("{}.{}", T_NAME, col)
+                }
+
+                /// Qualifies the columns of a serialized filter not qualified yet.
+                #[doc(hidden)]
+                pub fn qualify(filter: &mut riwaq::serde_json::Value) {
+                    let Some(filter) = filter.as_object_mut() else {
+                        return;
+                    };
+                    for (key, value) in filter.iter_mut() {
+                        match key.as_str() {
+                            "And" | "Or" => value.as_array_mut().into_iter().flatten().for_each(qualify),
+                            "Filter" => {
+                                let item = value.as_object_mut().and_then(|item| item.values_mut().next());
+                                if let Some(col) = item.and_then(|item| item.get_mut("col")) {
+                                    if let Some(name) = col.as_str().filter(|name| !name.contains('.')) {
+                                        *col = riwaq::serde_json::Value::from(qualified(name));
+                                    }
+                                }
+                            }
+                            _ => {}
                         }
-                        pub fn lt(value: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Lt{
-                                col: #field_names_str.to_string(),
-                                value: riwaq::serde_json::to_value(value).unwrap()
-                            })
+                    }
+                }
+
+                /// Error of a select expecting a single row.
+                #[derive(Debug, Clone, PartialEq)]
+                pub enum FetchError {
+                    NotFound,
+                    MultipleRows,
+                    Sql(String),
+                }
+                impl std::fmt::Display for FetchError {
+                    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                        match self {
+                            FetchError::NotFound => write!(f, "no row found in table '{}'", #t_path),
+                            FetchError::MultipleRows => write!(f, "multiple rows found in table '{}'", #t_path),
+                            FetchError::Sql(e) => f.write_str(e),
                         }
-                        pub fn lte(value: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Lte{
-                                col: #field_names_str.to_string(),
-                                value: riwaq::serde_json::to_value(value).unwrap()
-                            })
+                    }
+                }
+                impl std::error::Error for FetchError {}
+                impl From<String> for FetchError {
+                    fn from(e: String) -> Self {
+                        FetchError::Sql(e)
+                    }
+                }
+                impl From<FetchError> for String {
+                    fn from(e: FetchError) -> Self {
+                        e.to_string()
+                    }
+                }
+
+                /// Count of the rows.
+                pub fn count() -> Agg<i64> {
+                    Agg::new("Count", None)
+                }
+
+                /// Sort key of a select, built from a column's `asc()` or `desc()`.
+                #[derive(riwaq::serde::Serialize, Clone, Debug)]
+                pub struct OrderBy {
+                    col: String,
+                    dir: &'static str,
+                    nulls: Option<&'static str>,
+                }
+                impl OrderBy {
+                    fn new(col: &str, dir: &'static str) -> Self {
+                        Self {
+                            col: col.to_string(),
+                            dir,
+                            nulls: None,
                         }
-                        pub fn between(start: #field_types, end: #field_types) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Between{
-                                col: #field_names_str.to_string(),
-                                start: riwaq::serde_json::to_value(start).unwrap(),
-                                end: riwaq::serde_json::to_value(end).unwrap()
-                            })
+                    }
+                    pub fn nulls_first(self) -> Self {
+                        Self {
+                            nulls: Some("First"),
+                            ..self
                         }
-                        pub fn like(expr: String) -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::Like{
-                                col: #field_names_str.to_string(),
-                                expr: expr
-                            })
+                    }
+                    pub fn nulls_last(self) -> Self {
+                        Self {
+                            nulls: Some("Last"),
+                            ..self
                         }
-                        pub fn is_null() -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::IsNull{
-                                col: #field_names_str.to_string()
-                            })
+                    }
+
+                    #[doc(hidden)]
+                    pub fn col(&self) -> &str {
+                        &self.col
+                    }
+
+                    /// Pins the null ordering, nulls are taken as the largest values when unset.
+                    #[doc(hidden)]
+                    pub fn or_default_nulls(self) -> Self {
+                        let nulls = match self.dir {
+                            "Asc" => "Last",
+                            _ => "First",
+                        };
+                        Self {
+                            nulls: self.nulls.or(Some(nulls)),
+                            ..self
                         }
-                        pub fn is_not_null() -> super::SQLFilter {
-                            super::SQLFilter(riwaq::sql::FilterItem::IsNotNull{
-                                col: #field_names_str.to_string()
+                    }
+
+                    /// Parts of the same ordering on the column qualified with the table name.
+                    #[doc(hidden)]
+                    pub fn into_qualified(self) -> (String, &'static str, Option<&'static str>) {
+                        (qualified(&self.col), self.dir, self.nulls)
+                    }
+
+                    /// Ordering on a qualified column of any table, for selects joining tables.
+                    #[doc(hidden)]
+                    pub fn from_qualified(
+                        (col, dir, nulls): (String, &'static str, Option<&'static str>),
+                    ) -> Self {
+                        Self { col, dir, nulls }
+                    }
+
+                    /// Primary key ordering, making every row position unique, key columns are never null.
+                    #[doc(hidden)]
+                    pub fn key_order() -> Vec<Self> {
+                        vec![#(#pk_cols::asc().nulls_first(),)*]
+                    }
+
+                    /// Filter on the rows ordered after the row holding `values` in the `orders` columns,
+                    /// `None` when no row can follow it.
+                    #[doc(hidden)]
+                    pub fn after(
+                        orders: &[Self],
+                        values: &[riwaq::serde_json::Value],
+                    ) -> Option<riwaq::sql::FilterStmt<SQLFilter>> {
+                        let filter = |item| riwaq::sql::FilterStmt::Filter(SQLFilter(item));
+                        let branches = orders
+                            .iter()
+                            .zip(values)
+                            .enumerate()
+                            .filter_map(|(i, (order, value))| {
+                                let col = order.col.to_owned();
+                                let nulls_first = order.nulls == Some("First");
+                                let next = match (value.is_null(), nulls_first) {
+                                    (true, true) => filter(riwaq::sql::FilterItem::IsNotNull { col }),
+                                    (true, false) => return None,
+                                    (false, _) => {
+                                        let value = value.clone();
+                                        let next = filter(match order.dir {
+                                            "Asc" => riwaq::sql::FilterItem::Gt { col: col.to_owned(), value },
+                                            _ => riwaq::sql::FilterItem::Lt { col: col.to_owned(), value },
+                                        });
+                                        match nulls_first {
+                                            true => next,
+                                            false => riwaq::sql::FilterStmt::Or(vec![
+                                                next,
+                                                filter(riwaq::sql::FilterItem::IsNull { col }),
+                                            ]),
+                                        }
+                                    }
+                                };
+                                let mut same = orders[..i]
+                                    .iter()
+                                    .zip(values)
+                                    .map(|(order, value)| {
+                                        let col = order.col.to_owned();
+                                        filter(match value {
+                                            riwaq::serde_json::Value::Null => riwaq::sql::FilterItem::IsNull { col },
+                                            value => riwaq::sql::FilterItem::Eq { col, value: value.clone() },
+                                        })
+                                    })
+                                    .collect::<Vec<_>>();
+                                Some(match same.is_empty() {
+                                    true => next,
+                                    false => {
+                                        same.push(next);
+                                        riwaq::sql::FilterStmt::And(same)
+                                    }
+                                })
                             })
+                            .collect::<Vec<_>>();
+                        match branches.len() {
+                            0 => None,
+                            1 => branches.into_iter().next(),
+                            _ => Some(riwaq::sql::FilterStmt::Or(branches)),
                         }
                     }
-                )*
+                }
+
+                #(#col_mods)*
+
+                pub type PrimaryKey = #key_ty;
+
+                #check_fns
+
+                #crypto_fns
+
+                #history_fn
+
+                fn key_filter(key: PrimaryKey) -> riwaq::sql::FilterStmt<SQLFilter> {
+                    #key_filter
+                }
+
+                /// Which rows of a soft delete table a query sees.
+                #[derive(Clone, Copy, PartialEq, Debug)]
+                pub enum Deleted {
+                    Exclude,
+                    Include,
+                    Only,
+                }
+
+                #[doc(hidden)]
+                pub fn scope(filter: riwaq::serde_json::Value, deleted: Deleted) -> riwaq::serde_json::Value {
+                    #scope
+                }
+
+                /// Soft delete scoping of the `select_from` queries, whose second field is the `Deleted`.
+                #[allow(unused_macros)]
+                macro_rules! deleted_fns {
+                    ($deleted:ty) => {
+                        #deleted_fns
+                    };
+                }
+                #[allow(unused_imports)]
+                pub(crate) use deleted_fns;
+
+                pub async fn get(key: PrimaryKey) -> Result<Option<SelectAll>, String> {
+                    let mut query = SelectAll::find();
+                    query.0.filter = Some(key_filter(key));
+                    query
+                        .exec()
+                        .await
+                        .map(|rows| rows.into_iter().next())
+                }
+
+                pub async fn exists(key: PrimaryKey) -> Result<bool, String> {
+                    get(key).await.map(|row| row.is_some())
+                }
 
                 #[derive(riwaq::serde::Serialize)]
                 pub struct Insert {
-                    #(pub #field_names: #field_types,)*
+                    #(#insert_decls,)*
                 }
                 impl Insert {
-                    pub async fn exec(&self) -> Result<i64, String> {
-                        let s = riwaq::serde_json::json!(riwaq::sql::Insert {
-                            op: Some("Insert".to_string()),
-                            tbl: #t_name.to_string(),
-                            values: riwaq::serde_json::to_value(self).unwrap()
-                        });
-                        riwaq::sql::sql_exec(s).await
-                    }
+                    #insert_exec
                 }
 
                 pub mod Update {
 
-                    pub struct Update(riwaq::sql::Update<super::SQLFilter>);
+                    #update_err_ty
+
+                    #update_doc
+                    pub struct Update(riwaq::sql::Update<super::SQLFilter> #update_keyed_ty);
                     impl Update {
                         #(
-                            pub fn #field_names(self, value: #field_types) -> Update {
+                            pub fn #field_names(self, value: #nested_types) -> Update {
                                 let mut values = self.0.values;
-                                values.insert(#field_names_str.to_string(), riwaq::serde_json::to_value(value).unwrap());
+                                #update_sets
                                 Update(riwaq::sql::Update {
                                     values,
                                     ..self.0
-                                })
+                                } #update_keyed)
+                                #update_filters
                             }
                         )*
 
@@ -397,7 +2577,7 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     _ => riwaq::sql::FilterStmt::Filter(filter),
                                 }),
                                 ..self.0
-                            })
+                            } #update_keyed)
                         }
                         pub fn and_all<VEC>(self, filter: VEC) -> Self
                         where
@@ -414,7 +2594,7 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     ),
                                 }),
                                 ..self.0
-                            })
+                            } #update_keyed)
                         }
                         pub fn where_(self, filter: super::SQLFilter) -> Self {
                             self.and(filter)
@@ -426,7 +2606,7 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     _ => riwaq::sql::FilterStmt::Filter(filter),
                                 }),
                                 ..self.0
-                            })
+                            } #update_keyed)
                         }
                         pub fn or_any<VEC>(self, filter: VEC) -> Self
                         where
@@ -443,30 +2623,43 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     ),
                                 }),
                                 ..self.0
-                            })
+                            } #update_keyed)
                         }
 
-                        pub async fn exec(&self) -> Result<i64, String> {
-                            let s = riwaq::serde_json::json!(self.0);
-                            riwaq::sql::sql_exec(s).await
-                        }
+                        #restore_fn
+
+                        #update_exec
+                    }
+
+                    #restore_ctor
+
+                    pub fn by_key(key: super::PrimaryKey) -> Update {
+                        Update(riwaq::sql::Update {
+                            op: Some("Update".to_string()),
+                            tbl: #t_path.to_string(),
+                            values: std::collections::HashMap::new(),
+                            filter: Some(super::key_filter(key))
+                        } #update_by_key)
                     }
 
                     #(
-                        pub fn #field_names(value: #field_types) -> Update {
+                        pub fn #field_names(value: #nested_types) -> Update {
+                            let mut values = std::collections::HashMap::new();
+                            #update_sets
                             Update(riwaq::sql::Update {
                                 op: Some("Update".to_string()),
-                                tbl: #t_name.to_string(),
-                                values: std::collections::HashMap::from([(#field_names_str.to_string(), riwaq::serde_json::to_value(value).unwrap())]),
+                                tbl: #t_path.to_string(),
+                                values,
                                 filter: None
-                            })
+                            } #update_init)
+                            #update_filters
                         }
                     )*
                 }
 
                 pub mod Delete {
 
-                    pub struct Delete(riwaq::sql::Delete<super::SQLFilter>);
+                    pub struct Delete(riwaq::sql::Delete<super::SQLFilter> #delete_force_ty);
                     impl Delete {
                         pub fn and(self, filter: super::SQLFilter) -> Self {
                             Self(riwaq::sql::Delete {
@@ -475,7 +2668,7 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     _ => riwaq::sql::FilterStmt::Filter(filter),
                                 }),
                                 ..self.0
-                            })
+                            } #delete_force)
                         }
                         pub fn and_all<VEC>(self, filter: VEC) -> Self
                         where
@@ -492,7 +2685,7 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     ),
                                 }),
                                 ..self.0
-                            })
+                            } #delete_force)
                         }
                         pub fn or_any<VEC>(self, filter: VEC) -> Self
                         where
@@ -509,40 +2702,359 @@ pub fn table(attr: TokenStream, item: TokenStream) -> TokenStream {
                                     ),
                                 }),
                                 ..self.0
-                            })
+                            } #delete_force)
                         }
 
-                        pub async fn exec(&self) -> Result<i64, String> {
-                            let s = riwaq::serde_json::json!(self.0);
-                            riwaq::sql::sql_exec(s).await
-                        }
+                        #delete_force_fn
+
+                        #delete_exec
                     }
 
                     pub fn where_(filter: super::SQLFilter) -> Delete {
                         Delete(riwaq::sql::Delete {
                             op: Some("Delete".to_string()),
-                            tbl: #t_name.to_string(),
+                            tbl: #t_path.to_string(),
                             filter: Some(riwaq::sql::FilterStmt::Filter(filter))
-                        })
+                        } #delete_init)
+                    }
+
+                    pub fn by_key(key: super::PrimaryKey) -> Delete {
+                        Delete(riwaq::sql::Delete {
+                            op: Some("Delete".to_string()),
+                            tbl: #t_path.to_string(),
+                            filter: Some(super::key_filter(key))
+                        } #delete_init)
                     }
 
                     pub fn all_rows() -> Delete {
                         Delete(riwaq::sql::Delete {
                             op: Some("Delete".to_string()),
-                            tbl: #t_name.to_string(),
+                            tbl: #t_path.to_string(),
                             filter: None
-                        })
+                        } #delete_init)
                     }
                 }
 
+                #(#type_checks)*
+
                 #[no_mangle]
                 extern "C" fn #ddl_name() -> *const u8 {
-                    #ddl.as_ptr()
+                    #ddl_ptr
                 }
+
+                #history_export
             }
 
         }
     };
 
-    output.into()
+    quote! {
+        #schema_lock
+
+        #output
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::{parse::Parser, parse_quote};
+
+    use super::*;
+
+    fn field(tokens: proc_macro2::TokenStream) -> Field {
+        Field::parse_named.parse2(tokens).unwrap()
+    }
+
+    fn index(meta: Meta, field_col: Option<&str>) -> IndexDDL {
+        index_to_ddl(&meta, "users", false, field_col, &|f| f.to_string())
+    }
+
+    #[test]
+    fn indexes() {
+        let idx = index(parse_quote!(index(cols(org, email), unique)), None);
+        assert_eq!(idx.name, "users_org_email_key");
+        assert_eq!(idx.cols, ["org", "email"]);
+        assert!(idx.unique);
+        assert!(matches!(idx.op, DDLOp::Keep));
+        let idx = index(
+            parse_quote!(index(name = "by_name", renamed_from = "name_idx")),
+            Some("name"),
+        );
+        assert_eq!(idx.name, "by_name");
+        assert!(matches!(idx.op, DDLOp::Rename(from) if from == "name_idx"));
+        assert!(matches!(
+            index(parse_quote!(index(dropped)), Some("name")).op,
+            DDLOp::Drop
+        ));
+        assert!(matches!(
+            index(parse_quote!(unique(cols(email), drop)), None).op,
+            DDLOp::Drop
+        ));
+        assert_eq!(
+            index(parse_quote!(index), Some("name")).name,
+            "users_name_idx"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown index option 'nme', expected one of:")]
+    fn unknown_index_option() {
+        index(parse_quote!(index(nme = "x")), Some("name"));
+    }
+
+    #[test]
+    #[should_panic(expected = "field level index should not list cols(...)")]
+    fn field_index_with_cols() {
+        index(parse_quote!(index(cols(org))), Some("name"));
+    }
+
+    #[test]
+    #[should_panic(expected = "index should not be both renamed and dropped")]
+    fn renamed_and_dropped_index() {
+        index(
+            parse_quote!(index(renamed_from = "a", dropped)),
+            Some("name"),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "index 'dropped' does not take a value")]
+    fn dropped_index_with_value() {
+        index(parse_quote!(index(dropped = true)), Some("name"));
+    }
+
+    #[test]
+    fn encrypted_fields() {
+        let opts = |f| {
+            encrypted_opts(&field(f)).map(|(deterministic, provider)| {
+                (deterministic, provider.map(|p| quote!(#p).to_string()))
+            })
+        };
+        assert_eq!(opts(quote!(email: String)), None);
+        assert_eq!(
+            opts(quote!(#[encrypted] email: String)),
+            Some((false, None))
+        );
+        assert_eq!(
+            opts(quote!(#[encrypted(deterministic)] email: String)),
+            Some((true, None))
+        );
+        assert_eq!(
+            opts(quote!(#[encrypted(provider = keys::Vault, deterministic)] email: String)),
+            Some((true, Some("keys :: Vault".to_string())))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "encrypted should be declared as")]
+    fn invalid_encrypted_option() {
+        encrypted_opts(&field(
+            quote!(#[encrypted(provider = "Vault")] email: String),
+        ));
+    }
+
+    #[test]
+    fn sql_types() {
+        assert_eq!(sql_type("i32"), "INT");
+        assert_eq!(sql_type("u64"), "BIGINT UNSIGNED");
+        assert_eq!(sql_type("Vec<u8>"), "BLOB");
+        assert_eq!(sql_type("Value"), "JSON");
+        assert_eq!(sql_type("DateTime<Utc>"), "TIMESTAMP");
+        assert_eq!(sql_type("Uuid"), "CHAR(36)");
+        assert_eq!(sql_type("Decimal"), "DECIMAL(38, 10)");
+        assert_eq!(
+            rust_type(&parse_quote!(Option<chrono::DateTime<Utc>>)),
+            ("DateTime<Utc>".to_string(), true)
+        );
+        assert_eq!(
+            rust_type(&parse_quote!(Vec<u8>)),
+            ("Vec<u8>".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn sql_enum_types() {
+        assert_eq!(known_sql_type("String"), Some("VARCHAR(65535)"));
+        assert_eq!(known_sql_type("Status"), None);
+        assert!(is_sql_enum("Status"));
+        assert_eq!(sql_type("Status"), "Status");
+        assert!(!is_sql_enum("i32"));
+        assert!(!is_sql_enum("HashMap<String, i32>"));
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported type 'Vec<i32>'")]
+    fn unsupported_sql_type() {
+        sql_type("Vec<i32>");
+    }
+
+    #[test]
+    fn column_type_overrides() {
+        assert_eq!(column_type(&field(quote!(name: String)), "String"), None);
+        assert_eq!(
+            column_type(&field(quote!(#[varchar(64)] name: String)), "String").as_deref(),
+            Some("VARCHAR(64)")
+        );
+        assert_eq!(
+            column_type(&field(quote!(#[char(2)] code: String)), "String").as_deref(),
+            Some("CHAR(2)")
+        );
+        assert_eq!(
+            column_type(&field(quote!(#[column(len = 16)] data: Vec<u8>)), "Vec<u8>").as_deref(),
+            Some("VARBINARY(16)")
+        );
+        assert_eq!(
+            column_type(
+                &field(quote!(#[column(precision = 10, scale = 2)] price: Decimal)),
+                "Decimal"
+            )
+            .as_deref(),
+            Some("DECIMAL(10, 2)")
+        );
+        assert_eq!(
+            column_type(&field(quote!(#[column(ty = "TEXT")] bio: String)), "String").as_deref(),
+            Some("TEXT")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "column type 'TEXT' is not compatible with field type 'i32'")]
+    fn incompatible_column_type() {
+        column_type(&field(quote!(#[column(ty = "TEXT")] n: i32)), "i32");
+    }
+
+    #[test]
+    #[should_panic(expected = "column type should be overridden only once")]
+    fn column_type_overridden_twice() {
+        column_type(
+            &field(quote!(#[varchar(8)] #[column(len = 8)] s: String)),
+            "String",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "column type 'CHAR(16)' is too short for field type 'Uuid'")]
+    fn short_uuid_column() {
+        column_type(&field(quote!(#[char(16)] id: Uuid)), "Uuid");
+    }
+
+    #[test]
+    #[should_panic(expected = "column precision should be in 1..=65")]
+    fn decimal_scale_over_precision() {
+        column_type(
+            &field(quote!(#[column(precision = 4, scale = 6)] n: Decimal)),
+            "Decimal",
+        );
+    }
+
+    #[test]
+    fn flattened_fields() {
+        assert_eq!(flatten_prefix(&field(quote!(home: Address))), None);
+        assert_eq!(
+            flatten_prefix(&field(quote!(#[flatten] home: Address))).as_deref(),
+            Some("home_")
+        );
+        assert_eq!(
+            flatten_prefix(&field(quote!(#[flatten(prefix = "h")] home: Address))).as_deref(),
+            Some("h")
+        );
+        let path = embedded_path(&parse_quote!(models::Address));
+        assert_eq!(
+            quote!(#path).to_string(),
+            "models :: __riwaq_embedded_address"
+        );
+        assert_eq!(
+            nested_path(&path, 2).to_string(),
+            "super :: super :: models :: __riwaq_embedded_address"
+        );
+        assert_eq!(
+            nested_path(&parse_quote!(crate::Address), 2).to_string(),
+            "crate :: Address"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "flatten should be declared as")]
+    fn invalid_flatten_prefix() {
+        flatten_prefix(&field(quote!(#[flatten(name = "h")] home: Address)));
+    }
+
+    #[test]
+    #[should_panic(expected = "flattened field should be a struct marked with #[embedded]")]
+    fn optional_flattened_field() {
+        embedded_path(&parse_quote!(Option<Address>));
+    }
+
+    #[test]
+    fn column_orderings() {
+        let fns = |encrypted| column_filters(&quote!(i32), encrypted).to_string();
+        assert!(fns(None).contains("pub fn asc () -> super :: OrderBy"));
+        assert!(fns(None).contains("pub fn desc () -> super :: OrderBy"));
+        // ciphertexts do not sort like their values
+        assert!(!fns(Some(true)).contains("fn asc"));
+        assert!(fns(Some(true)).contains("fn eq"));
+        assert!(!fns(Some(false)).contains("fn desc"));
+        assert!(!fns(Some(false)).contains("fn eq"));
+    }
+
+    #[test]
+    fn range_checks() {
+        let checks = |f| field_checks(&field(f), "n", "i32").0;
+        assert_eq!(
+            checks(quote!(#[range(0..=10)] n: i32)),
+            ["n >= 0 AND n <= 10"]
+        );
+        assert_eq!(checks(quote!(#[range(-5..)] n: i32)), ["n >= -5"]);
+        assert_eq!(checks(quote!(#[range(..100)] n: i32)), ["n < 100"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range should have at least one bound")]
+    fn unbounded_range() {
+        field_checks(&field(quote!(#[range(..)] n: i32)), "n", "i32");
+    }
+
+    #[test]
+    fn default_values() {
+        assert_eq!(default_value(&parse_quote!("a"), "String"), json!("a"));
+        assert_eq!(default_value(&parse_quote!('c'), "char"), json!("c"));
+        assert_eq!(default_value(&parse_quote!(3), "i32"), json!(3));
+        assert_eq!(default_value(&parse_quote!(-3), "i64"), json!(-3));
+        assert_eq!(
+            default_value(&parse_quote!(18446744073709551615), "u64"),
+            json!(u64::MAX)
+        );
+        assert_eq!(default_value(&parse_quote!(3), "f64"), json!(3.0));
+        assert_eq!(default_value(&parse_quote!(-1.5), "f32"), json!(-1.5));
+        assert_eq!(default_value(&parse_quote!(true), "bool"), json!(true));
+        assert_eq!(
+            default_value(&parse_quote!(CURRENT_TIMESTAMP), "NaiveDateTime"),
+            json!({ "expr": "CURRENT_TIMESTAMP" })
+        );
+        assert_eq!(
+            default_value(&parse_quote!(sql = "now() + 1"), "NaiveDateTime"),
+            json!({ "expr": "now() + 1" })
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "default value of type string literal should only be used on fields of type"
+    )]
+    fn mismatched_default_value() {
+        default_value(&parse_quote!("a"), "i32");
+    }
+
+    #[test]
+    #[should_panic(expected = "default value of type float should only be used on fields of type")]
+    fn float_default_on_int() {
+        default_value(&parse_quote!(1.5), "i32");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid default value")]
+    fn unsigned_negative_default_value() {
+        default_value(&parse_quote!(-1), "u8");
+    }
 }
@@ -0,0 +1,321 @@
+use proc_macro2::Ident;
+use riwaq_types::sql::TableDDLOp;
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Expr, ExprLit, Lit, Meta, Token,
+};
+
+/// Options accepted by `#[table(...)]`.
+pub struct TableOpts {
+    pub op: Option<TableDDLOp>,
+    pub name: Option<String>,
+    pub schema: Option<String>,
+    pub db: Option<String>,
+    pub primary_key: Option<Vec<Ident>>,
+    pub indexes: Vec<(Meta, bool)>,
+    pub soft_delete: bool,
+    pub audited: bool,
+}
+
+impl TableOpts {
+    pub fn is_drop(&self) -> bool {
+        matches!(self.op, Some(TableDDLOp::Drop) | Some(TableDDLOp::DropAll))
+    }
+}
+
+const TABLE_OPTIONS: &str = "'name', 'schema', 'db', 'renamed_from', 'primary_key', 'index', 'unique', 'soft_delete', 'audited', 'drop', 'drop_table_and_data', 'undrop'";
+
+fn str_value(meta: &Meta, key: &str) -> syn::Result<String> {
+    match meta {
+        Meta::NameValue(nv) => match &nv.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => Ok(s.value()),
+            v => Err(syn::Error::new_spanned(
+                v,
+                format!("table option '{}' should be a str literal", key),
+            )),
+        },
+        _ => Err(syn::Error::new_spanned(
+            meta,
+            format!(
+                "table option '{}' should be given as: {} = \"...\"",
+                key, key
+            ),
+        )),
+    }
+}
+
+fn flag(meta: &Meta, key: &str) -> syn::Result<()> {
+    match meta {
+        Meta::Path(_) => Ok(()),
+        _ => Err(syn::Error::new_spanned(
+            meta,
+            format!("table option '{}' does not take a value", key),
+        )),
+    }
+}
+
+impl Parse for TableOpts {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut opts = TableOpts {
+            op: None,
+            name: None,
+            schema: None,
+            db: None,
+            primary_key: None,
+            indexes: vec![],
+            soft_delete: false,
+            audited: false,
+        };
+        let mut op_key: Option<String> = None;
+        let mut seen: Vec<String> = vec![];
+
+        for meta in Punctuated::<Meta, Token![,]>::parse_terminated(input)? {
+            let path = meta.path();
+            let key = path
+                .get_ident()
+                .ok_or_else(|| {
+                    syn::Error::new_spanned(path, "table option should be an identifier")
+                })?
+                .to_string()
+                .to_lowercase();
+
+            let op = match key.as_str() {
+                "dropped_table_and_data" | "drop_table_and_data" => {
+                    flag(&meta, &key)?;
+                    Some(TableDDLOp::DropAll)
+                }
+                "dropped" | "drop" => {
+                    flag(&meta, &key)?;
+                    Some(TableDDLOp::Drop)
+                }
+                "undropped" | "undrop" => {
+                    flag(&meta, &key)?;
+                    Some(TableDDLOp::Undrop)
+                }
+                "renamed_from" | "rename_from" => Some(TableDDLOp::Rename(str_value(&meta, &key)?)),
+                _ => None,
+            };
+            if let Some(op) = op {
+                if let Some(prev) = &op_key {
+                    return Err(syn::Error::new_spanned(
+                        path,
+                        format!("conflicting table options '{}' and '{}'", prev, key),
+                    ));
+                }
+                op_key = Some(key);
+                opts.op = Some(op);
+                continue;
+            }
+
+            if [
+                "name",
+                "schema",
+                "db",
+                "primary_key",
+                "soft_delete",
+                "audited",
+            ]
+            .contains(&key.as_str())
+            {
+                if seen.contains(&key) {
+                    return Err(syn::Error::new_spanned(
+                        path,
+                        format!("duplicate table option '{}'", key),
+                    ));
+                }
+                seen.push(key.to_owned());
+            }
+            match key.as_str() {
+                "name" => opts.name = Some(str_value(&meta, &key)?),
+                "schema" => opts.schema = Some(str_value(&meta, &key)?),
+                "db" => opts.db = Some(str_value(&meta, &key)?),
+                "primary_key" => {
+                    let list = meta.require_list()?;
+                    let pk = list
+                        .parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    if pk.is_empty() {
+                        return Err(syn::Error::new_spanned(
+                            list,
+                            "primary_key should list at least one field",
+                        ));
+                    }
+                    opts.primary_key = Some(pk);
+                }
+                "index" => {
+                    meta.require_list()?;
+                    opts.indexes.push((meta, false))
+                }
+                "unique" => {
+                    meta.require_list()?;
+                    opts.indexes.push((meta, true))
+                }
+                "soft_delete" => {
+                    flag(&meta, &key)?;
+                    opts.soft_delete = true
+                }
+                "audited" => {
+                    flag(&meta, &key)?;
+                    opts.audited = true
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        path,
+                        format!(
+                            "unknown table option '{}', expected one of: {}",
+                            key, TABLE_OPTIONS
+                        ),
+                    ))
+                }
+            }
+        }
+
+        Ok(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proc_macro2::TokenStream;
+    use quote::quote;
+
+    use super::*;
+
+    fn parse(tokens: TokenStream) -> TableOpts {
+        match syn::parse2::<TableOpts>(tokens) {
+            Ok(opts) => opts,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    fn parse_err(tokens: TokenStream) -> String {
+        match syn::parse2::<TableOpts>(tokens) {
+            Ok(_) => panic!("table options should not parse"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn options() {
+        let opts = parse(quote!(
+            name = "users",
+            schema = "app",
+            db = "main",
+            primary_key(org, id),
+            index(cols(name)),
+            unique(cols(email)),
+            soft_delete,
+            audited,
+            renamed_from = "user"
+        ));
+        assert_eq!(opts.name.as_deref(), Some("users"));
+        assert_eq!(opts.schema.as_deref(), Some("app"));
+        assert_eq!(opts.db.as_deref(), Some("main"));
+        assert!(parse(quote!()).db.is_none());
+        assert_eq!(opts.primary_key.unwrap().len(), 2);
+        assert_eq!(
+            opts.indexes.iter().map(|i| i.1).collect::<Vec<_>>(),
+            [false, true]
+        );
+        assert!(opts.soft_delete && opts.audited);
+        assert!(!parse(quote!()).soft_delete && !parse(quote!()).audited);
+        assert!(matches!(opts.op, Some(TableDDLOp::Rename(from)) if from == "user"));
+
+        assert!(parse(quote!(dropped)).is_drop());
+        assert!(parse(quote!(drop_table_and_data)).is_drop());
+        assert!(!parse(quote!(undrop)).is_drop());
+        assert!(parse(quote!()).op.is_none());
+    }
+
+    #[test]
+    fn combined_options() {
+        let opts = parse(quote!(
+            name = "x",
+            renamed_from = "y",
+            soft_delete,
+            db = "main"
+        ));
+        assert_eq!(opts.name.as_deref(), Some("x"));
+        assert!(matches!(opts.op, Some(TableDDLOp::Rename(from)) if from == "y"));
+        assert!(opts.soft_delete);
+        assert_eq!(opts.db.as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn unknown_option() {
+        assert!(parse_err(quote!(nme = "users")).starts_with("unknown table option 'nme'"));
+        assert_eq!(
+            parse_err(quote!(a::b)),
+            "table option should be an identifier"
+        );
+    }
+
+    #[test]
+    fn duplicate_option() {
+        assert_eq!(
+            parse_err(quote!(name = "a", name = "b")),
+            "duplicate table option 'name'"
+        );
+        assert_eq!(
+            parse_err(quote!(db = "a", db = "b")),
+            "duplicate table option 'db'"
+        );
+        assert_eq!(
+            parse_err(quote!(primary_key(id), primary_key(id))),
+            "duplicate table option 'primary_key'"
+        );
+        assert_eq!(
+            parse_err(quote!(soft_delete, soft_delete)),
+            "duplicate table option 'soft_delete'"
+        );
+        assert_eq!(
+            parse_err(quote!(audited, audited)),
+            "duplicate table option 'audited'"
+        );
+    }
+
+    #[test]
+    fn conflicting_ops() {
+        assert_eq!(
+            parse_err(quote!(drop, renamed_from = "user")),
+            "conflicting table options 'drop' and 'renamed_from'"
+        );
+        assert_eq!(
+            parse_err(quote!(dropped, undropped)),
+            "conflicting table options 'dropped' and 'undropped'"
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(
+            parse_err(quote!(name = users)),
+            "table option 'name' should be a str literal"
+        );
+        assert_eq!(
+            parse_err(quote!(db = main)),
+            "table option 'db' should be a str literal"
+        );
+        assert_eq!(
+            parse_err(quote!(schema)),
+            "table option 'schema' should be given as: schema = \"...\""
+        );
+        assert_eq!(
+            parse_err(quote!(drop = true)),
+            "table option 'drop' does not take a value"
+        );
+        assert_eq!(
+            parse_err(quote!(soft_delete = true)),
+            "table option 'soft_delete' does not take a value"
+        );
+        assert_eq!(
+            parse_err(quote!(primary_key())),
+            "primary_key should list at least one field"
+        );
+        assert!(parse_err(quote!(index)).contains("expected attribute arguments in parentheses"));
+    }
+}